@@ -1,6 +1,6 @@
 fn main() {
     for elem in std::env::args().skip(1) {
-        pnm::decode(&std::fs::read(elem).unwrap())
+        pnm::open(elem)
             .unwrap()
             .rgba()
             .show();
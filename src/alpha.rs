@@ -0,0 +1,142 @@
+//! conversion between straight and premultiplied (associated) alpha, and compositing over a background.
+use alloc::vec::Vec;
+use fimg::Image;
+
+/// Multiply the color channels of this RGBA image by its alpha, in place, rounding to the nearest integer.
+///
+/// ```
+/// # use fimg::Image;
+/// let mut x = Image::<_, 4>::build(1, 1).buf(vec![255, 100, 0, 128]);
+/// pnm::premultiply(&mut x);
+/// assert_eq!(x.buffer(), &[128, 50, 0, 128]);
+/// ```
+pub fn premultiply<T: AsMut<[u8]> + AsRef<[u8]>>(x: &mut Image<T, 4>) {
+    for [r, g, b, a] in x.flatten_mut() {
+        for c in [r, g, b] {
+            *c = ((*c as u16 * *a as u16 + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Divide the color channels of this premultiplied RGBA image by its alpha, in place, rounding to the nearest integer.
+///
+/// Fully transparent pixels keep a color of 0.
+pub fn unpremultiply<T: AsMut<[u8]> + AsRef<[u8]>>(x: &mut Image<T, 4>) {
+    for [r, g, b, a] in x.flatten_mut() {
+        for c in [r, g, b] {
+            *c = match *a {
+                0 => 0,
+                255 => *c,
+                a => ((*c as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8,
+            };
+        }
+    }
+}
+
+/// `c * a + background * (1 - a)`, rounding to the nearest integer.
+const fn over(c: u8, a: u8, background: u8) -> u8 {
+    ((c as u16 * a as u16 + background as u16 * (255 - a) as u16 + 127) / 255) as u8
+}
+
+/// Composite this RGBA image over a solid `background` color, and encode it to a raw [`ppm`](crate::ppm).
+///
+/// Unlike [`encode()`](crate::encode), which drops the alpha channel, transparent pixels take on the `background`.
+///
+/// ```
+/// # use fimg::Image;
+/// let x = Image::<_, 4>::build(2, 1).buf(&[255, 0, 0, 255, 255, 0, 0, 0][..]);
+/// let out = pnm::encode_over(x, [255, 255, 255]);
+/// assert_eq!(out, b"P6 2 1 255\n\xff\x00\x00\xff\xff\xff");
+/// ```
+pub fn encode_over<T: AsRef<[u8]>>(x: Image<T, 4>, background: [u8; 3]) -> Vec<u8> {
+    let x = x.as_ref();
+    let buf = x
+        .flatten()
+        .iter()
+        .flat_map(|&[r, g, b, a]| {
+            [
+                over(r, a, background[0]),
+                over(g, a, background[1]),
+                over(b, a, background[2]),
+            ]
+        })
+        .collect::<Vec<_>>();
+    crate::ppm::raw::encode(Image::<_, 3>::build(x.width(), x.height()).buf(buf))
+}
+
+/// Composite this grey-alpha image over a solid `background` grey, and encode it to a raw [`pgm`](crate::pgm).
+///
+/// See [`encode_over`].
+pub fn encode_over_grey<T: AsRef<[u8]>>(x: Image<T, 2>, background: u8) -> Vec<u8> {
+    let x = x.as_ref();
+    let buf = x
+        .flatten()
+        .iter()
+        .map(|&[y, a]| over(y, a, background))
+        .collect::<Vec<_>>();
+    crate::pgm::raw::encode(Image::<_, 1>::build(x.width(), x.height()).buf(buf))
+}
+
+#[test]
+fn test_roundtrip() {
+    let original = include_bytes!("../tdata/fimg-rainbow-transparent.imgbuf");
+    let mut x = Image::<_, 4>::build(20, 15).buf(original.to_vec());
+    premultiply(&mut x);
+    unpremultiply(&mut x);
+    for (a, b) in x.flatten().iter().zip(original.chunks_exact(4)) {
+        assert_eq!(a[3], b[3]);
+        for (&x, &y) in a[..3].iter().zip(&b[..3]) {
+            // premultiplying loses precision at low alpha
+            assert!(x.abs_diff(y) as u32 * b[3] as u32 <= 255, "{a:?} {b:?}");
+        }
+    }
+}
+
+#[test]
+fn test_edges() {
+    let mut x = Image::<_, 4>::build(2, 1).buf(vec![10, 20, 30, 255, 10, 20, 30, 0]);
+    premultiply(&mut x);
+    assert_eq!(x.buffer(), &[10, 20, 30, 255, 0, 0, 0, 0]);
+    let mut x = Image::<_, 4>::build(2, 1).buf(vec![10, 20, 30, 255, 10, 20, 30, 0]);
+    unpremultiply(&mut x);
+    assert_eq!(x.buffer(), &[10, 20, 30, 255, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_over() {
+    // half transparent rainbow
+    let mut x = include_bytes!("../tdata/fimg-rainbow-transparent.imgbuf").to_vec();
+    x.chunks_exact_mut(4).for_each(|x| x[3] /= 2);
+    let x = Image::<_, 4>::build(20, 15).buf(x);
+
+    // over black is premultiplied
+    let mut black = x.clone();
+    premultiply(&mut black);
+    let out = crate::decode(encode_over(x.as_ref(), [0; 3])).unwrap();
+    assert!(out
+        .bytes()
+        .chunks_exact(3)
+        .eq(black.buffer().chunks_exact(4).map(|x| &x[..3])));
+
+    // over white is the inverse of the inverse over black
+    let mut white = x.clone();
+    white.flatten_mut().iter_mut().for_each(|[r, g, b, _]| {
+        for c in [r, g, b] {
+            *c = 255 - *c;
+        }
+    });
+    premultiply(&mut white);
+    let out = crate::decode(encode_over(x.as_ref(), [255; 3])).unwrap();
+    assert!(out.bytes().iter().copied().eq(white
+        .buffer()
+        .chunks_exact(4)
+        .flat_map(|x| x[..3].iter().map(|&x| 255 - x))));
+
+    let y = Image::<_, 2>::build(3, 1).buf(&[200, 255, 200, 0, 200, 128][..]);
+    assert_eq!(
+        &**crate::pgm::raw::decode(encode_over_grey(y, 100))
+            .unwrap()
+            .buffer(),
+        [200, 100, 150]
+    );
+}
@@ -1,18 +1,38 @@
 //! decoding utilities
-use std::num::NonZeroU32;
+use core::num::NonZeroU32;
 
 pub(crate) trait Read {
     fn rd<const N: usize>(&mut self) -> Option<[u8; N]>;
     fn by(&mut self) -> Option<u8> {
         Some(self.rd::<1>()?[0])
     }
+    /// Next byte, without consuming it.
+    fn peek(&mut self) -> Option<u8>;
 }
-impl<T: std::io::Read> Read for T {
+#[cfg(feature = "std")]
+impl<T: std::io::BufRead> Read for T {
     fn rd<const N: usize>(&mut self) -> Option<[u8; N]> {
         let mut buf = [0; N];
         self.read_exact(&mut buf).ok()?;
         Some(buf)
     }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.fill_buf().ok()?.first().copied()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Read for &[u8] {
+    fn rd<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (&x, rest) = self.split_first_chunk()?;
+        *self = rest;
+        Some(x)
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.first().copied()
+    }
 }
 
 pub(crate) trait Ten {
@@ -28,6 +48,7 @@ macro_rules! tenz {
     };
 }
 tenz!(u8);
+tenz!(u16);
 tenz!(u32);
 
 pub(crate) trait Ck
@@ -51,39 +72,70 @@ macro_rules! cks {
     };
 }
 cks!(u8);
+cks!(u16);
 cks!(u32);
 
 /// Result alias with [`Error`].
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
 
 pub(crate) fn read_til<
     T: Default
         + Ck
-        + std::ops::Mul<T, Output = T>
-        + std::ops::Add<T, Output = T>
+        + core::ops::Mul<T, Output = T>
+        + core::ops::Add<T, Output = T>
         + From<u8>
         + Copy
         + Ten,
 >(
-    x: &mut &[u8],
+    x: &mut impl Read,
 ) -> Result<T> {
     let mut n = T::default();
-    while let Some(x) = x.by() {
-        if x.is_ascii_whitespace() {
+    while let Some(b) = x.peek() {
+        // a comment may follow a number directly, and ends it like whitespace would
+        if b == b'#' {
+            while x.by().is_some_and(|b| b != b'\n') {}
             return Ok(n);
         }
-        if !x.is_ascii_digit() {
-            return Err(Error::NotDigit(x as char));
+        // leave anything that isnt a digit unconsumed, so errors point at it
+        if !b.is_ascii_digit() && !b.is_ascii_whitespace() {
+            return Err(Error::NotDigit(b as char));
+        }
+        x.by();
+        if b.is_ascii_whitespace() {
+            return Ok(n);
         }
         n = n
             .checked_mul(T::ten())
             .ok_or(Error::Overflow)?
-            .checked_add(T::from(x - b'0'))
+            .checked_add(T::from(b - b'0'))
             .ok_or(Error::Overflow)?;
     }
     Ok(n)
 }
 
+/// Advances `x` by `n` bytes (or to the end).
+pub(crate) fn skip(x: &mut &[u8], n: usize) {
+    *x = &x[n.min(x.len())..];
+}
+
+/// Length of a plain body holding `n` whitespace separated samples.
+pub(crate) fn plain_len(x: &[u8], n: usize) -> usize {
+    let mut seen = 0;
+    let mut in_token = false;
+    for (i, &b) in x.iter().enumerate() {
+        if b.is_ascii_whitespace() {
+            if in_token && seen == n {
+                return i;
+            }
+            in_token = false;
+        } else if !in_token {
+            in_token = true;
+            seen += 1;
+        }
+    }
+    x.len()
+}
+
 macro_rules! dec_fn {
     ($($f:ident)? $doc:literal) => {
         use crate::decode::{decode_header, Error, Result};
@@ -110,6 +162,98 @@ macro_rules! dec_fn {
 }
 pub(crate) use dec_fn;
 
+/// Options for decoding.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeOptions {
+    pub(crate) strict: bool,
+    pub(crate) max_ops: u64,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeOptions {
+    /// Lenient options, same as [`Default`].
+    pub const fn new() -> Self {
+        Self {
+            strict: false,
+            max_ops: u64::MAX,
+        }
+    }
+
+    /// Reject malformed input that is otherwise tolerated, such as data trailing a [`pam`](crate::pam) body,
+    /// or a numeric [`pam`](crate::pam) header field given twice (lenient decoding takes the last one).
+    pub const fn strict(self, strict: bool) -> Self {
+        Self { strict, ..self }
+    }
+
+    /// Give up with [`Error::BudgetExceeded`] after processing `max_ops` bytes of an image, to bound the time spent on untrusted input.
+    pub const fn max_ops(self, max_ops: u64) -> Self {
+        Self { max_ops, ..self }
+    }
+
+    /// Budget left after processing `n` bytes.
+    pub(crate) fn spend(&self, n: usize) -> Result<u64> {
+        self.max_ops
+            .checked_sub(n as u64)
+            .ok_or(Error::BudgetExceeded)
+    }
+}
+
+/// Byte iterator that stops (and remembers) when its budget is spent.
+///
+/// One byte past the budget may be pulled, as lookahead. It is only charged if it is used (see [`Budget::finish`]).
+pub(crate) struct Budget<I> {
+    x: I,
+    max: u64,
+    pulled: u64,
+    exceeded: bool,
+}
+
+impl<I: Iterator<Item = u8>> Budget<I> {
+    pub(crate) const fn new(x: I, max: u64) -> Self {
+        Self {
+            x,
+            max,
+            pulled: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Bytes pulled so far.
+    pub(crate) const fn pulled(&self) -> usize {
+        self.pulled as usize
+    }
+
+    /// Prefers [`Error::BudgetExceeded`], if the budget ran out, or `used` bytes go past it.
+    pub(crate) fn finish<T>(self, used: usize, x: Result<T>) -> Result<T> {
+        match self.exceeded || used as u64 > self.max {
+            true => Err(Error::BudgetExceeded),
+            false => x,
+        }
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for Budget<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.exceeded {
+            return None;
+        }
+        if self.pulled > self.max {
+            self.exceeded = self.x.next().is_some();
+            return None;
+        }
+        let b = self.x.next()?;
+        self.pulled += 1;
+        Some(b)
+    }
+}
+
 /// Header for the older PNM formats. Not applicable to PAM.
 #[derive(Debug, Clone, Copy)]
 pub struct Header {
@@ -117,8 +261,156 @@ pub struct Header {
     pub magic: u8,
     pub width: NonZeroU32,
     pub height: NonZeroU32,
-    /// Maximum value of each byte.
-    pub max: Option<u8>,
+    /// Maximum value of each sample, up to 65535. Raw samples take two bytes when it is over 255.
+    pub max: Option<u16>,
+}
+
+impl Header {
+    /// Header of a `width` by `height` image of this format, with a max of 255 unless it is a [`pbm`](crate::pbm).
+    ///
+    /// # Panics
+    ///
+    /// If `width` or `height` is 0, or the format is [`Format::Pam`], which uses a [`PAMHeader`](crate::pam::PAMHeader).
+    pub const fn new(format: Format, width: u32, height: u32) -> Self {
+        assert!(!matches!(format, Format::Pam), "use a PAMHeader");
+        Self {
+            magic: format.magic(),
+            width: NonZeroU32::new(width).expect("zero width"),
+            height: NonZeroU32::new(height).expect("zero height"),
+            max: match format {
+                Format::PbmPlain | Format::PbmRaw => None,
+                _ => Some(255),
+            },
+        }
+    }
+
+    /// Set the max value. Does nothing for a [`pbm`](crate::pbm), which has none.
+    pub const fn with_max(self, max: u16) -> Self {
+        Self {
+            max: match self.max {
+                Some(_) => Some(max),
+                None => None,
+            },
+            ..self
+        }
+    }
+}
+
+impl Default for Header {
+    /// A 1x1 raw [`ppm`](crate::ppm) header.
+    fn default() -> Self {
+        Self::new(Format::PpmRaw, 1, 1)
+    }
+}
+
+/// Format of a pnm image, from [`format`](crate::format).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub enum Format {
+    /// `P1`
+    PbmPlain,
+    /// `P4`
+    PbmRaw,
+    /// `P2`
+    PgmPlain,
+    /// `P5`
+    PgmRaw,
+    /// `P3`
+    PpmPlain,
+    /// `P6`
+    PpmRaw,
+    /// `P7`
+    Pam,
+}
+
+impl Format {
+    /// Format with this magic number.
+    pub const fn from_magic(x: u8) -> Option<Self> {
+        Some(match x {
+            1 => Self::PbmPlain,
+            2 => Self::PgmPlain,
+            3 => Self::PpmPlain,
+            4 => Self::PbmRaw,
+            5 => Self::PgmRaw,
+            6 => Self::PpmRaw,
+            7 => Self::Pam,
+            _ => return None,
+        })
+    }
+
+    /// Magic number.
+    pub const fn magic(self) -> u8 {
+        match self {
+            Self::PbmPlain => 1,
+            Self::PgmPlain => 2,
+            Self::PpmPlain => 3,
+            Self::PbmRaw => 4,
+            Self::PgmRaw => 5,
+            Self::PpmRaw => 6,
+            Self::Pam => 7,
+        }
+    }
+}
+
+/// Header of any pnm image, from [`probe`](crate::probe).
+#[derive(Debug, Clone, Copy)]
+pub enum Info {
+    /// [`pbm`](crate::pbm), [`pgm`](crate::pgm), or [`ppm`](crate::ppm) header.
+    Pnm(Header),
+    /// [`pam`](crate::pam) header.
+    Pam(crate::pam::PAMHeader),
+}
+
+impl Info {
+    /// Magic number.
+    pub const fn magic(&self) -> u8 {
+        match self {
+            Self::Pnm(x) => x.magic,
+            Self::Pam(_) => crate::pam::MAGIC,
+        }
+    }
+
+    /// Format, from the magic number.
+    pub const fn format(&self) -> Format {
+        match Format::from_magic(self.magic()) {
+            Some(x) => x,
+            // headers are only made for known magic numbers
+            None => unreachable!(),
+        }
+    }
+
+    pub const fn width(&self) -> NonZeroU32 {
+        match self {
+            Self::Pnm(x) => x.width,
+            Self::Pam(x) => x.width,
+        }
+    }
+
+    pub const fn height(&self) -> NonZeroU32 {
+        match self {
+            Self::Pnm(x) => x.height,
+            Self::Pam(x) => x.height,
+        }
+    }
+
+    /// Channel count of the decoded image.
+    pub const fn channels(&self) -> u8 {
+        match self {
+            Self::Pnm(x) if x.magic % 3 == 0 => 3,
+            Self::Pnm(_) => 1,
+            Self::Pam(x) => x.depth,
+        }
+    }
+
+    /// Maximum value of each sample. This is 1 for PBM.
+    pub const fn max(&self) -> u16 {
+        match self {
+            Self::Pnm(Header { max: Some(x), .. }) => *x,
+            Self::Pnm(_) => 1,
+            Self::Pam(x) => x.max as u16,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -128,48 +420,148 @@ pub enum Error {
     TooLarge,
     NotDigit(char),
     BadMagic(u8),
-    WrongMagic { got: u8, should: u8 },
+    WrongMagic {
+        got: u8,
+        should: u8,
+    },
+    OffsetOutOfBounds {
+        offset: usize,
+        len: usize,
+    },
     MissingMagic,
     ZeroWidth,
     ZeroHeight,
+    ZeroMax,
     MissingWidth,
     MissingHeight,
     MissingData,
     MissingMax,
     MissingDepth,
     MissingTupltype,
+    DepthMismatch {
+        depth: u8,
+        expected: u8,
+    },
+    TrailingData {
+        extra: usize,
+    },
+    UnsupportedDepth(u8),
+    BufferSize {
+        expected: usize,
+        got: usize,
+    },
+    BudgetExceeded,
+    OverMax {
+        sample: u16,
+        max: u16,
+    },
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
+    BadScale,
     Overflow,
+    DuplicateField(&'static str),
+    LongTupltype,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::TooLarge => write!(f, "image too big"),
-            Self::NotDigit(x) => write!(f, "found {x} while decoding number"),
+            Self::NotDigit(x) => write!(f, "found {x:?} while decoding number"),
             Self::BadMagic(x) => write!(f, "{x} is not a valid magic number"),
             Self::WrongMagic { got, should } => {
                 write!(f, "expected magic number {should} found {got}")
             }
+            Self::OffsetOutOfBounds { offset, len } => {
+                write!(f, "offset {offset} out of bounds for input of length {len}")
+            }
             Self::MissingMagic => write!(f, "no magic number (likely not a pnm image)"),
             Self::ZeroWidth => write!(f, "zero width"),
             Self::ZeroHeight => write!(f, "zero height"),
+            Self::ZeroMax => write!(f, "zero max value"),
             Self::MissingWidth => write!(f, "no width"),
             Self::MissingHeight => write!(f, "no height"),
             Self::MissingData => write!(f, "no data"),
             Self::MissingMax => write!(f, "no max value"),
             Self::MissingDepth => write!(f, "no depth"),
             Self::MissingTupltype => write!(f, "no tupltype"),
+            Self::DepthMismatch { depth, expected } => {
+                write!(f, "depth {depth} does not match tupltype depth {expected}")
+            }
+            Self::TrailingData { extra } => write!(f, "{extra} bytes of trailing data"),
+            Self::UnsupportedDepth(x) => write!(f, "{x} channels are not supported"),
+            Self::BufferSize { expected, got } => {
+                write!(f, "expected a buffer of {expected} bytes, got {got}")
+            }
+            Self::BudgetExceeded => write!(f, "operation budget exceeded"),
+            Self::OverMax { sample, max } => write!(f, "sample {sample} exceeds maxval {max}"),
+            #[cfg(feature = "std")]
+            Self::Io(x) => write!(f, "io error: {x}"),
+            Self::BadScale => write!(f, "scale is not a nonzero finite number"),
             Self::Overflow => write!(f, "overflow while parsing number"),
+            Self::DuplicateField(x) => write!(f, "{x} given more than once"),
+            Self::LongTupltype => write!(
+                f,
+                "tupltype longer than {} bytes",
+                crate::pam::TypeName::MAX
+            ),
+        }
+    }
+}
+impl core::error::Error for Error {}
+
+/// An [`Error`], and the byte offset into the input where decoding stopped.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorAt {
+    pub error: Error,
+    pub offset: usize,
+}
+
+impl ErrorAt {
+    /// Locates `error`, which occurred with `rest` left of `input`.
+    pub(crate) const fn new(error: Error, input: &[u8], rest: &[u8]) -> Self {
+        let offset = match error {
+            // the magic number is the very first thing
+            Error::MissingMagic | Error::BadMagic(_) => 0,
+            // ran out of input
+            Error::MissingData => input.len(),
+            _ => input.len() - rest.len(),
+        };
+        Self { error, offset }
+    }
+}
+
+impl core::fmt::Display for ErrorAt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} at byte {}", self.error, self.offset)
+    }
+}
+
+impl core::error::Error for ErrorAt {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(x: std::io::Error) -> Self {
+        match x.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::MissingData,
+            x => Self::Io(x),
         }
     }
 }
-impl std::error::Error for Error {}
 
 /// Decodes the magic number.
 pub fn magic(x: &mut &[u8]) -> Option<u8> {
+    magic_from(x)
+}
+
+pub(crate) fn magic_from(x: &mut impl Read) -> Option<u8> {
     (x.by()? == b'P').then_some(())?;
     let m = x.by().and_then(|x| x.checked_sub(b'0'));
-    while x.first()?.is_ascii_whitespace() {
+    while x.peek()?.is_ascii_whitespace() {
         x.by();
     }
     m
@@ -177,25 +569,26 @@ pub fn magic(x: &mut &[u8]) -> Option<u8> {
 
 /// Get the older pnm formats header. Does not decode magic.
 pub fn decode_header(x: &mut &[u8], magic: u8) -> Result<Header> {
-    while x.first() == Some(&b'#') {
-        while let Some(b) = x.by()
-            && b != b'\n'
-        {}
-    }
+    header_from(x, magic)
+}
+
+pub(crate) fn header_from(x: &mut impl Read, magic: u8) -> Result<Header> {
+    skip_comments(x);
     let width = NonZeroU32::new(read_til(x)?).ok_or(Error::ZeroWidth)?;
+    skip_comments(x);
     let height = NonZeroU32::new(read_til(x)?).ok_or(Error::ZeroHeight)?;
     width.checked_mul(height).ok_or(Error::TooLarge)?;
     let max = if magic != 4 && magic != 1 {
-        Some(read_til(x)?)
+        skip_comments(x);
+        match read_til::<u16>(x)? {
+            0 => return Err(Error::ZeroMax),
+            x => Some(x),
+        }
     } else {
         None
     };
-
-    if magic != 4 {
-        while x.first().ok_or(Error::MissingData)?.is_ascii_whitespace() {
-            x.by();
-        }
-    }
+    // raw formats are separated from their body by the single whitespace `read_til` consumed,
+    // plain formats skip any further whitespace as they tokenize.
     Ok(Header {
         magic,
         width,
@@ -203,3 +596,212 @@ pub fn decode_header(x: &mut &[u8], magic: u8) -> Result<Header> {
         max,
     })
 }
+
+/// Lazily parses whitespace separated samples, each at most `max`.
+///
+/// Tokens may be any length (`0255` is 255), but must be digits and fit in a [`u16`].
+/// Each bad token is an error.
+pub(crate) const fn tokens<I: Iterator<Item = u8>>(x: I, max: u16) -> Tokens<I> {
+    Tokens {
+        x,
+        peeked: None,
+        max,
+        at: 0,
+        start: 0,
+    }
+}
+
+/// Iterator from [`tokens`], which counts the bytes it consumes.
+pub(crate) struct Tokens<I> {
+    x: I,
+    peeked: Option<u8>,
+    max: u16,
+    at: usize,
+    start: usize,
+}
+
+impl<I: Iterator<Item = u8>> Tokens<I> {
+    /// Offset of the last token, or of the end, if it ran out.
+    pub(crate) const fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Bytes consumed, not counting the one peeked at.
+    pub(crate) const fn used(&self) -> usize {
+        self.at
+    }
+
+    /// These samples, scaled from `max` to 255.
+    pub(crate) fn scaled(&mut self) -> impl Iterator<Item = Result<u8>> + '_ {
+        let max = self.max;
+        self.map(move |x| x.map(|x| rescale(x, max)))
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        if self.peeked.is_none() {
+            self.peeked = self.x.next();
+        }
+        self.peeked
+    }
+
+    fn next_if(&mut self, f: impl FnOnce(&u8) -> bool) -> Option<u8> {
+        let b = self.peek().filter(f)?;
+        self.peeked = None;
+        self.at += 1;
+        Some(b)
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for Tokens<I> {
+    type Item = Result<u16>;
+
+    fn next(&mut self) -> Option<Result<u16>> {
+        while self.next_if(u8::is_ascii_whitespace).is_some() {}
+        self.start = self.at;
+        self.peek()?;
+        let mut n = Ok(0u16);
+        // consume the whole token, even if it is bad
+        while let Some(b) = self.next_if(|x| !x.is_ascii_whitespace()) {
+            n = n.and_then(|n| match b {
+                b'0'..=b'9' => n
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add((b - b'0') as u16))
+                    .ok_or(Error::Overflow),
+                _ => Err(Error::NotDigit(b as char)),
+            });
+        }
+        let max = self.max;
+        Some(n.and_then(|sample| match sample > max {
+            true => Err(Error::OverMax { sample, max }),
+            false => Ok(sample),
+        }))
+    }
+}
+
+/// Skips whitespace and `#` comments, which may appear between any header tokens.
+pub(crate) fn skip_comments(x: &mut impl Read) {
+    loop {
+        match x.peek() {
+            Some(b'#') => while x.by().is_some_and(|b| b != b'\n') {},
+            Some(b) if b.is_ascii_whitespace() => {
+                x.by();
+            }
+            _ => break,
+        }
+    }
+}
+
+/// Writes the first `n` samples to `out`, stopping at the first error.
+/// # Safety
+///
+/// `out` must have space for `n` bytes.
+pub(crate) unsafe fn put_samples(
+    x: impl Iterator<Item = Result<u8>>,
+    n: usize,
+    mut out: *mut u8,
+) -> Result<()> {
+    let mut left = n;
+    for b in x.take(n) {
+        // SAFETY: takes `n` samples.
+        unsafe { crate::encode::P::push(&mut out, b?) };
+        left -= 1;
+    }
+    match left {
+        0 => Ok(()),
+        _ => Err(Error::MissingData),
+    }
+}
+
+/// Whitespace separated plain samples, scaled from `max` to 255. See [`tokens`].
+pub(crate) fn samples(x: impl Iterator<Item = u8>, max: u16) -> impl Iterator<Item = Result<u8>> {
+    tokens(x, max).map(move |x| x.map(|x| rescale(x, max)))
+}
+
+/// Bytes taken by each raw sample: two (big endian) for a maxval over 255.
+pub(crate) const fn sample_bytes(max: u16) -> usize {
+    match max {
+        0..=255 => 1,
+        _ => 2,
+    }
+}
+
+/// Raw samples, scaled from `max` to 255.
+pub(crate) fn raw_samples(x: &[u8], max: u16) -> impl Iterator<Item = u8> + '_ {
+    x.chunks_exact(sample_bytes(max)).map(move |x| {
+        let x = match *x {
+            [x] => x as u16,
+            [a, b] => u16::from_be_bytes([a, b]),
+            _ => unreachable!(),
+        };
+        rescale(x, max)
+    })
+}
+
+/// [`raw_samples`] of a whole raw body.
+#[cfg(feature = "std")]
+pub(crate) fn rescaled(x: Vec<u8>, max: u16) -> Vec<u8> {
+    match max {
+        255 => x,
+        _ => raw_samples(&x, max).collect(),
+    }
+}
+
+/// Rescales a `0..=max` sample into `0..=255`, rounding to the nearest value, like [`scale`](crate::encode::scale) does the other way.
+///
+/// Samples over `max` become 255.
+pub(crate) const fn rescale(x: u16, max: u16) -> u8 {
+    let x = (x as u32 * 255 + max as u32 / 2) / max as u32;
+    if x > 255 {
+        255
+    } else {
+        x as u8
+    }
+}
+
+/// Reads exactly `n` bytes.
+#[cfg(feature = "std")]
+pub(crate) fn read_n(x: &mut impl std::io::Read, n: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; n];
+    x.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Bytes of a reader, stopping at (and keeping) the first io error.
+#[cfg(feature = "std")]
+pub(crate) struct Bytes<'a, R> {
+    x: std::io::Bytes<&'a mut R>,
+    error: Option<std::io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, R: std::io::Read> Bytes<'a, R> {
+    pub(crate) fn new(x: &'a mut R) -> Self {
+        Self {
+            x: std::io::Read::bytes(x),
+            error: None,
+        }
+    }
+
+    /// Prefers the io error, if there was one.
+    pub(crate) fn finish<T>(self, x: Result<T>) -> Result<T> {
+        match self.error {
+            Some(e) => Err(e.into()),
+            None => x,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Iterator for Bytes<'_, R> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        match self.x.next()? {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
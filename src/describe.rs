@@ -0,0 +1,106 @@
+//! structured summaries of images.
+use crate::decode::{Format, Result};
+use alloc::vec::Vec;
+
+/// Summary of a decoded image, for cataloging and diffing.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ImageDescription {
+    /// Format, e.g. [`Format::PpmRaw`].
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    /// Channel count of the decoded image.
+    pub channels: u8,
+    /// Maximum value, as declared by the header. (`1` for PBM.)
+    pub max: u16,
+    /// Statistics for each channel of the decoded image.
+    pub stats: Vec<ChannelStats>,
+    /// 64 bit FNV-1a hash of the decoded pixel buffer.
+    pub hash: u64,
+}
+
+/// Statistics of one channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelStats {
+    pub min: u8,
+    pub max: u8,
+    pub mean: f64,
+}
+
+/// Describe any [`pgm`](crate::pgm), [`ppm`](crate::ppm), [`pbm`](crate::pbm), [`pam`](crate::pam) image.
+///
+/// ```
+/// let d = pnm::describe(include_bytes!("../tdata/fimgR.pgm")).unwrap();
+/// assert_eq!((d.width, d.height, d.channels), (20, 15, 1));
+/// ```
+pub fn describe(x: impl AsRef<[u8]>) -> Result<ImageDescription> {
+    let x = x.as_ref();
+    let info = crate::probe(x)?;
+    let image = crate::decode(x)?;
+    let buf = image.bytes();
+    let channels = info.channels() as usize;
+    let stats = (0..channels)
+        .map(|c| {
+            let (min, max, sum) = buf
+                .iter()
+                .skip(c)
+                .step_by(channels)
+                .fold((u8::MAX, 0, 0u64), |(min, max, sum), &x| {
+                    (min.min(x), max.max(x), sum + x as u64)
+                });
+            ChannelStats {
+                min,
+                max,
+                mean: sum as f64 / (buf.len() / channels) as f64,
+            }
+        })
+        .collect();
+    Ok(ImageDescription {
+        format: info.format(),
+        width: image.width(),
+        height: image.height(),
+        channels: info.channels(),
+        max: info.max(),
+        stats,
+        hash: buf.iter().fold(0xcbf29ce484222325, |h, &x| {
+            (h ^ x as u64).wrapping_mul(0x100000001b3)
+        }),
+    })
+}
+
+#[test]
+fn test_describe() {
+    let d = describe(include_bytes!("../tdata/fimg-rainbowR.ppm")).unwrap();
+    assert_eq!(
+        (d.format, d.width, d.height, d.channels, d.max),
+        (Format::PpmRaw, 20, 15, 3, 255)
+    );
+    let data = include_bytes!("../tdata/fimg-rainbow.imgbuf");
+    for (c, stats) in d.stats.iter().enumerate() {
+        let channel = data.iter().skip(c).step_by(3).copied();
+        assert_eq!(stats.min, channel.clone().min().unwrap());
+        assert_eq!(stats.max, channel.clone().max().unwrap());
+        assert_eq!(
+            stats.mean,
+            channel.map(|x| x as f64).sum::<f64>() / (20. * 15.)
+        );
+    }
+    assert_eq!(
+        d.hash,
+        describe(include_bytes!("../tdata/fimg-rainbowA.ppm"))
+            .unwrap()
+            .hash
+    );
+    assert_ne!(
+        d.hash,
+        describe(include_bytes!("../tdata/fimg-rainbow-transparent.pam"))
+            .unwrap()
+            .hash
+    );
+    assert_eq!(
+        describe(include_bytes!("../tdata/fimgR.pbm")).unwrap().max,
+        1
+    );
+}
@@ -1,4 +1,55 @@
-pub trait P<T: Copy> {
+//! encoding utilities
+use alloc::vec::Vec;
+use atools::prelude::*;
+use core::convert::Infallible;
+
+/// Result alias with [`Error`].
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+/// Errors that can occur on encoding.
+pub enum Error {
+    /// The row order given to [`encode_reordered`](crate::encode_reordered) is not a permutation of the rows.
+    BadRowOrder,
+    /// The tupltype given to [`pam::encode_custom`](crate::pam::encode_custom) is longer than [`TypeName::MAX`](crate::pam::TypeName::MAX) bytes.
+    LongTupltype,
+    /// The tupltype given to [`pam::encode_custom`](crate::pam::encode_custom) has a control character, such as a newline.
+    BadTupltype,
+    /// [`encode_raw`](crate::encode_raw) was given a channel count outside `1..=4`.
+    UnsupportedDepth(u8),
+    /// [`encode_raw`](crate::encode_raw) was given a width of 0.
+    ZeroWidth,
+    /// [`encode_raw`](crate::encode_raw) was given a height of 0.
+    ZeroHeight,
+    /// The image given to [`encode_raw`](crate::encode_raw) has more bytes than fit in memory.
+    TooLarge,
+    /// The buffer given to [`encode_raw`](crate::encode_raw) does not hold `width * height * channels` bytes.
+    BufferSize { expected: usize, got: usize },
+    /// The scale given to [`pfm::encode`](crate::pfm::encode) is zero or not finite.
+    BadScale,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BadRowOrder => write!(f, "row order is not a permutation of the rows"),
+            Self::LongTupltype => write!(f, "tupltype too long"),
+            Self::BadTupltype => write!(f, "tupltype has a control character"),
+            Self::UnsupportedDepth(x) => write!(f, "{x} channels are not supported"),
+            Self::ZeroWidth => write!(f, "zero width"),
+            Self::ZeroHeight => write!(f, "zero height"),
+            Self::TooLarge => write!(f, "image too big"),
+            Self::BufferSize { expected, got } => {
+                write!(f, "expected a buffer of {expected} bytes, got {got}")
+            }
+            Self::BadScale => write!(f, "scale is zero or not finite"),
+        }
+    }
+}
+impl core::error::Error for Error {}
+
+pub(crate) trait P<T: Copy> {
     unsafe fn put<const N: usize>(&mut self, x: [T; N]);
     #[cfg_attr(debug_assertions, track_caller)]
     unsafe fn push(&mut self, x: T) {
@@ -14,7 +65,153 @@ impl<T: Copy> P<T> for *mut T {
     }
 }
 
-pub const fn encode_bool(x: bool) -> u8 {
+/// Bytes taken by `comment` as `# ` prefixed lines.
+pub(crate) fn comment_size(comment: &str) -> usize {
+    comment.len() + comment.bytes().filter(|&x| x == b'\n').count() * 2 + 3
+}
+
+/// Checks that `comment` can go in a header: each line becomes a `# ` prefixed line.
+///
+/// # Panics
+///
+/// If `comment` has control characters other than `\n` and `\t`.
+pub(crate) fn check_comment(comment: &str) {
+    assert!(
+        !comment
+            .bytes()
+            .any(|x| x.is_ascii_control() && x != b'\n' && x != b'\t'),
+        "comment may not contain control characters"
+    );
+}
+
+/// Where the encoders write: a [`Vec`], a writer (through [`Io`]), or a raw pointer (through [`Ptr`]).
+///
+/// Each format has one body routine over a [`Sink`], so `encode`, `encode_to` and `encode_into` always agree.
+pub(crate) trait Sink {
+    type Error;
+    /// Makes room for `n` more bytes, where that helps.
+    fn reserve(&mut self, _n: usize) {}
+    fn put(&mut self, x: &[u8]) -> core::result::Result<(), Self::Error>;
+}
+
+impl Sink for Vec<u8> {
+    type Error = Infallible;
+    fn reserve(&mut self, n: usize) {
+        Self::reserve(self, n);
+    }
+    fn put(&mut self, x: &[u8]) -> core::result::Result<(), Infallible> {
+        self.extend_from_slice(x);
+        Ok(())
+    }
+}
+
+/// [`Sink`] over a writer.
+#[cfg(feature = "std")]
+pub(crate) struct Io<'a, W>(pub &'a mut W);
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> Sink for Io<'_, W> {
+    type Error = std::io::Error;
+    fn put(&mut self, x: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(x)
+    }
+}
+
+/// [`Sink`] over a raw pointer.
+pub(crate) struct Ptr {
+    start: *mut u8,
+    at: *mut u8,
+}
+
+impl Ptr {
+    /// # Safety
+    ///
+    /// `out` must have room for everything put.
+    pub(crate) const unsafe fn new(out: *mut u8) -> Self {
+        Self {
+            start: out,
+            at: out,
+        }
+    }
+
+    /// Bytes put so far.
+    pub(crate) const fn written(&self) -> usize {
+        // SAFETY: `at` only moves forward from `start`.
+        unsafe { self.at.sub_ptr(self.start) }
+    }
+}
+
+impl Sink for Ptr {
+    type Error = Infallible;
+    fn put(&mut self, x: &[u8]) -> core::result::Result<(), Infallible> {
+        // SAFETY: new() promised room.
+        unsafe {
+            self.at.copy_from(x.as_ptr(), x.len());
+            self.at = self.at.add(x.len());
+        }
+        Ok(())
+    }
+}
+
+/// Unwraps the result of a [`Sink`] that cannot fail.
+pub(crate) fn done<T>(x: core::result::Result<T, Infallible>) -> T {
+    match x {
+        Ok(x) => x,
+        Err(x) => match x {},
+    }
+}
+
+/// Longest header [`header`] writes, without a comment: `P5 4294967295 4294967295 65535\n`.
+pub(crate) const HEADER: usize = 31;
+
+/// Puts the `P{magic} {width} {height} {max}\n` header, without a max for PBM.
+///
+/// Each line of `comment` goes on its own `# ` prefixed line after the magic number.
+pub(crate) fn header<S: Sink>(
+    magic: u8,
+    (w, h): (u32, u32),
+    max: Option<u16>,
+    comment: Option<&str>,
+    to: &mut S,
+) -> core::result::Result<(), S::Error> {
+    to.put(&b'P'.join(magic + b'0'))?;
+    match comment {
+        Some(comment) => {
+            to.put(b"\n")?;
+            for line in comment.split('\n') {
+                to.put(b"# ")?;
+                to.put(line.as_bytes())?;
+                to.put(b"\n")?;
+            }
+        }
+        None => to.put(b" ")?,
+    }
+    let mut buf = [0; HEADER];
+    let start = buf.as_mut_ptr();
+    let mut o = start;
+    // SAFETY: the numbers take at most HEADER bytes.
+    let n = unsafe {
+        encodeu32(w, &mut o);
+        o.push(b' ');
+        encodeu32(h, &mut o);
+        if let Some(max) = max {
+            o.push(b' ');
+            encodeu32(max as u32, &mut o);
+        }
+        o.push(b'\n');
+        o.sub_ptr(start)
+    };
+    to.put(&buf[..n])
+}
+
+/// A [`header`], with room for `body` more bytes.
+pub(crate) fn with_header(magic: u8, dims: (u32, u32), max: Option<u16>, body: usize) -> Vec<u8> {
+    let mut y = Vec::with_capacity(HEADER + body);
+    done(header(magic, dims, max, None, &mut y));
+    y
+}
+
+pub(crate) const fn encode_bool(x: bool) -> u8 {
     (x as u8) + b'0'
 }
 
@@ -53,12 +250,46 @@ const MAGIC: [u32; 256] = [
     540554290, 540619826, 540030258, 540095794, 540161330, 540226866, 540292402, 540357938,
 ];
 
+/// Rescales a `0..=255` sample into `0..=max`, rounding to the nearest value.
+pub(crate) const fn scale(x: u8, max: u16) -> u16 {
+    ((x as u32 * max as u32 + 127) / 255) as u16
+}
+
+/// Puts the raw samples of `row`, rescaled into `0..=max`: a byte each, or two (big endian) when `max` is over 255.
+pub(crate) fn raw_row(row: &[u8], max: u16, to: &mut Vec<u8>) {
+    match max {
+        0..=255 => to.extend(row.iter().map(|&x| scale(x, max) as u8)),
+        _ => to.extend(row.iter().flat_map(|&x| scale(x, max).to_be_bytes())),
+    }
+}
+
+/// Puts the plain samples of `row`, rescaled into `0..=max`, each followed by spaces.
+pub(crate) fn plain_row(row: &[u8], max: u16, to: &mut Vec<u8>) {
+    match max {
+        0..=255 => to.extend(row.iter().flat_map(|&x| encode_(scale(x, max) as u8))),
+        _ => {
+            for &x in row {
+                let mut buf = [0; 6];
+                let start = buf.as_mut_ptr();
+                let mut o = start;
+                // SAFETY: a u16 and a space take at most 6 bytes.
+                let n = unsafe {
+                    encodeu32(scale(x, max) as u32, &mut o);
+                    o.push(b' ');
+                    o.sub_ptr(start)
+                };
+                to.extend_from_slice(&buf[..n]);
+            }
+        }
+    }
+}
+
 /// Has a space. (_)
-pub const fn encode_(x: u8) -> [u8; 4] {
+pub(crate) const fn encode_(x: u8) -> [u8; 4] {
     MAGIC[x as usize].to_le_bytes()
 }
 
-pub unsafe fn encodeu32(mut x: u32, buf: &mut *mut u8) {
+pub(crate) unsafe fn encodeu32(mut x: u32, buf: &mut *mut u8) {
     let mut tmp = [0; 10];
     let mut tp = tmp.as_mut_ptr();
     while x >= 10 {
@@ -0,0 +1,150 @@
+//! [`image`] crate decoder and encoder implementations.
+use crate::decode::{self, Info};
+use image::error::{
+    DecodingError, EncodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind,
+};
+use image::{ColorType, ExtendedColorType, ImageError, ImageFormat, ImageResult};
+
+const HINT: ImageFormatHint = ImageFormatHint::Exact(ImageFormat::Pnm);
+
+/// An [`image::ImageDecoder`] for any [`pgm`](crate::pgm), [`ppm`](crate::ppm), [`pbm`](crate::pbm), or [`pam`](crate::pam) image.
+///
+/// ```
+/// let x = include_bytes!("../tdata/fimg-rainbowR.ppm");
+/// let out = image::DynamicImage::from_decoder(pnm::Decoder::new(x).unwrap()).unwrap();
+/// assert_eq!(out.color(), image::ColorType::Rgb8);
+/// ```
+pub struct Decoder<'a> {
+    x: &'a [u8],
+    info: Info,
+}
+
+impl<'a> Decoder<'a> {
+    /// Reads the header. The body is decoded by [`read_image`](image::ImageDecoder::read_image).
+    pub fn new(x: &'a [u8]) -> ImageResult<Self> {
+        let info = crate::probe(x).map_err(decoding)?;
+        if info.channels() > 4 {
+            return Err(decoding(decode::Error::UnsupportedDepth(info.channels())));
+        }
+        Ok(Self { x, info })
+    }
+}
+
+impl image::ImageDecoder for Decoder<'_> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.info.width().get(), self.info.height().get())
+    }
+
+    fn color_type(&self) -> ColorType {
+        match self.info.channels() {
+            1 => ColorType::L8,
+            2 => ColorType::La8,
+            3 => ColorType::Rgb8,
+            _ => ColorType::Rgba8,
+        }
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        crate::decode_to_slice(self.x, buf)
+            .map(drop)
+            .map_err(decoding)
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+/// An [`image::ImageEncoder`] writing a [`pgm`](crate::pgm) or [`ppm`](crate::ppm) when there is no alpha, and a [`pam`](crate::pam) otherwise.
+///
+/// ```
+/// # use image::{DynamicImage, RgbaImage};
+/// let x = DynamicImage::ImageRgba8(RgbaImage::new(4, 4));
+/// let mut out = vec![];
+/// x.write_with_encoder(pnm::Encoder::new(&mut out)).unwrap();
+/// assert!(out.starts_with(b"P7"));
+/// ```
+pub struct Encoder<W> {
+    to: W,
+}
+
+impl<W: std::io::Write> Encoder<W> {
+    pub const fn new(to: W) -> Self {
+        Self { to }
+    }
+}
+
+impl<W: std::io::Write> image::ImageEncoder for Encoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        let channels = match color_type {
+            ExtendedColorType::L8 => 1,
+            ExtendedColorType::La8 => 2,
+            ExtendedColorType::Rgb8 => 3,
+            ExtendedColorType::Rgba8 => 4,
+            x => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(HINT, UnsupportedErrorKind::Color(x)),
+                ))
+            }
+        };
+        let out = crate::encode_raw(buf, width, height, channels)
+            .map_err(|x| ImageError::Encoding(EncodingError::new(HINT, x)))?;
+        self.to.write_all(&out).map_err(ImageError::IoError)
+    }
+}
+
+/// [`decode_into`](crate::decode_into) an [`image::DynamicImage`].
+///
+/// ```
+/// let out: image::DynamicImage = pnm::decode_into(include_bytes!("../tdata/fimgR.pgm")).unwrap();
+/// assert_eq!(out.color(), image::ColorType::L8);
+/// ```
+impl crate::Decode for image::DynamicImage {
+    fn decode(x: &[u8]) -> decode::Result<Self> {
+        use fimg::DynImage;
+        use image::ImageBuffer;
+        // fimg images hold exactly width * height pixels, so from_raw cannot fail
+        Ok(match crate::decode(x)? {
+            DynImage::Y(x) => Self::ImageLuma8(
+                ImageBuffer::from_raw(x.width(), x.height(), x.take_buffer()).unwrap(),
+            ),
+            DynImage::Ya(x) => Self::ImageLumaA8(
+                ImageBuffer::from_raw(x.width(), x.height(), x.take_buffer()).unwrap(),
+            ),
+            DynImage::Rgb(x) => Self::ImageRgb8(
+                ImageBuffer::from_raw(x.width(), x.height(), x.take_buffer()).unwrap(),
+            ),
+            DynImage::Rgba(x) => Self::ImageRgba8(
+                ImageBuffer::from_raw(x.width(), x.height(), x.take_buffer()).unwrap(),
+            ),
+        })
+    }
+}
+
+fn decoding(x: decode::Error) -> ImageError {
+    ImageError::Decoding(DecodingError::new(HINT, x))
+}
+
+#[test]
+fn test_roundtrip() {
+    use image::DynamicImage;
+    for &(_, x) in crate::FIXTURES {
+        let expected = crate::decode(x).unwrap();
+        let image = DynamicImage::from_decoder(Decoder::new(x).unwrap()).unwrap();
+        assert_eq!(image.as_bytes(), expected.bytes());
+        assert_eq!(crate::decode_into::<DynamicImage>(x).unwrap(), image);
+        assert_eq!(
+            image.color().channel_count() as usize * 20 * 15,
+            expected.bytes().len()
+        );
+        let mut out = vec![];
+        image.write_with_encoder(Encoder::new(&mut out)).unwrap();
+        assert_eq!(crate::decode(out).unwrap().bytes(), expected.bytes());
+    }
+}
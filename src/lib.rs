@@ -3,13 +3,24 @@
 //! ### a quick guide to the various functions for everyday use
 //!
 //! - [`decode()`]: your go-to for all PNM image decoding.
-//! If you have a specific format you need to support, use its module directly.
-//! Note that this function reads both plain and raw formats.
+//!   If you have a specific format you need to support, use its module directly.
+//!   Note that this function reads both plain and raw formats.
+//! - [`decode_borrowed`]: [`decode()`], but borrowing raw bodies from the input instead of copying them.
+//! - [`open`]: [`decode()`], but from a file. [`write_to_path`] goes the other way.
+//! - [`decode_into`]: [`decode()`], but into a type of your choosing, such as an <code>[Image]<[Vec]<[u8]>, 3></code>.
 //! - [`encode()`]: this function is a little tricky.
-//! It supports the "older" PNM formats, and, due to their age they do not support the alpha channels existence.
-//! If possible, use [`pam::encode`] instead.
+//!   It supports the "older" PNM formats, and, due to their age they do not support the alpha channels existence.
+//!   If possible, use [`pam::encode`] instead, or [`encode_over`] to composite onto a background.
 //! - [`encode_plain`]: The `PAM` format doesnt actually support read-age-by-humans, so this is still useful at times.
-//! Outputs data in decimal digits.
+//!   Outputs data in decimal digits.
+//!
+//! ### features
+//!
+//! - `std` (default): [`decode_reader`], [`open`], and the `encode_to` family, which work with [`std::io`].
+//!   Without it, this crate is `no_std`, and only needs `alloc`.
+//! - `half`: [`f16`](https://docs.rs/half/latest/half/struct.f16.html) support in [`pfm`].
+//! - `image`: a `Decoder` and `Encoder` for the [`image`](https://docs.rs/image) crate.
+//! - `serde`: [`Serialize`](https://docs.rs/serde/latest/serde/trait.Serialize.html) for [`ImageDescription`].
 //!
 //! ### functions in action
 //!
@@ -19,6 +30,7 @@
 //!
 //! assert_eq!(pnm::encode(out), data);
 //! ```
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![allow(incomplete_features)]
 #![feature(ptr_sub_ptr, let_chains, iter_array_chunks)]
 #![warn(
@@ -28,39 +40,401 @@
     clippy::use_self
 )]
 
+extern crate alloc;
+use alloc::{borrow::Cow, boxed::Box, string::String, vec::Vec};
+use decode::DecodeOptions;
 use fimg::{uninit, DynImage, Image};
+mod alpha;
 pub mod decode;
-pub(crate) mod encode;
+mod describe;
+pub mod encode;
+#[cfg(feature = "image")]
+mod interop;
 pub mod pam;
 pub mod pbm;
+pub mod pfm;
 pub mod pgm;
 pub mod ppm;
+pub use alpha::{encode_over, encode_over_grey, premultiply, unpremultiply};
+pub use describe::{describe, ChannelStats, ImageDescription};
+#[cfg(feature = "image")]
+pub use interop::{Decoder, Encoder};
 
 /// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image.
 pub fn decode(x: impl AsRef<[u8]>) -> decode::Result<DynImage<Vec<u8>>> {
+    decode_from(&mut x.as_ref(), DecodeOptions::new())
+}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image, with some [`DecodeOptions`].
+///
+/// ```
+/// # use pnm::decode::{DecodeOptions, Error};
+/// let x = include_bytes!("../tdata/fimgA.pgm");
+/// assert!(matches!(
+///     pnm::decode_with(x, DecodeOptions::new().max_ops(100)),
+///     Err(Error::BudgetExceeded)
+/// ));
+/// ```
+pub fn decode_with(
+    x: impl AsRef<[u8]>,
+    options: DecodeOptions,
+) -> decode::Result<DynImage<Vec<u8>>> {
+    decode_from(&mut x.as_ref(), options)
+}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image, borrowing the pixels from `x` when they are already laid out as [`decode()`] would.
+///
+/// Raw [`pgm`], [`ppm`], and non `BLACKANDWHITE` [`pam`] bodies with a maxval of 255 are borrowed, without a copy.
+/// Plain formats, and the bit formats, which need expanding, are decoded into a [`Cow::Owned`].
+///
+/// ```
+/// # use std::borrow::Cow;
+/// # use fimg::DynImage;
+/// let data = include_bytes!("../tdata/fimg-rainbowR.ppm");
+/// let DynImage::Rgb(out) = pnm::decode_borrowed(data).unwrap() else { panic!() };
+/// assert!(matches!(out.buffer(), Cow::Borrowed(_)));
+/// ```
+pub fn decode_borrowed(x: &[u8]) -> decode::Result<DynImage<Cow<'_, [u8]>>> {
+    use decode::{Error, Info};
+    let mut rest = x;
+    let info = probe_from(&mut rest)?;
+    let (w, h) = (info.width().get(), info.height().get());
+    let borrow = match &info {
+        Info::Pnm(header) => {
+            matches!(header.magic, pgm::raw::MAGIC | ppm::raw::MAGIC) && header.max == Some(255)
+        }
+        Info::Pam(header) => {
+            !matches!(header.tupltype, pam::Type::Bit | pam::Type::BitA) && header.max == 255
+        }
+    };
+    let buf = match borrow {
+        true => {
+            let n = w as usize * h as usize * info.channels() as usize;
+            Cow::Borrowed(rest.get(..n).ok_or(Error::MissingData)?)
+        }
+        false => Cow::Owned(e!(decode(x)?, |x| x.take_buffer())),
+    };
+    Ok(match info.channels() {
+        1 => DynImage::Y(Image::build(w, h).buf(buf)),
+        2 => DynImage::Ya(Image::build(w, h).buf(buf)),
+        3 => DynImage::Rgb(Image::build(w, h).buf(buf)),
+        4 => DynImage::Rgba(Image::build(w, h).buf(buf)),
+        x => return Err(Error::UnsupportedDepth(x)),
+    })
+}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image, with errors that say where decoding stopped.
+///
+/// ```
+/// let e = pnm::decode_located(b"P5 20 1x 255\n").unwrap_err();
+/// assert_eq!(e.offset, 7);
+/// assert_eq!(e.to_string(), "found 'x' while decoding number at byte 7");
+/// ```
+pub fn decode_located(x: impl AsRef<[u8]>) -> Result<DynImage<Vec<u8>>, decode::ErrorAt> {
+    let x = x.as_ref();
+    let mut rest = x;
+    decode_from(&mut rest, DecodeOptions::new())
+        .map_err(|error| decode::ErrorAt::new(error, x, rest))
+}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image that starts `offset` bytes into `x`.
+///
+/// Returns the image, and the offset of the first byte after it.
+///
+/// ```
+/// let mut container = b"HEADER".to_vec();
+/// container.extend(include_bytes!("../tdata/fimgR.pgm"));
+/// let (out, end) = pnm::decode_at_offset(&container, 6).unwrap();
+/// assert_eq!((out.width(), out.height()), (20, 15));
+/// assert_eq!(end, container.len());
+/// ```
+pub fn decode_at_offset(x: &[u8], offset: usize) -> decode::Result<(DynImage<Vec<u8>>, usize)> {
+    let mut rest = x.get(offset..).ok_or(decode::Error::OffsetOutOfBounds {
+        offset,
+        len: x.len(),
+    })?;
+    let image = decode_from(&mut rest, DecodeOptions::new())?;
+    Ok((image, x.len() - rest.len()))
+}
+
+/// Decode every image of a concatenated multi image stream, such as the output of `pnmcat`.
+///
+/// Images may be of different formats, and whitespace between them is skipped.
+/// A truncated (or otherwise broken) image yields an error, after which the iterator ends.
+///
+/// ```
+/// let mut x = include_bytes!("../tdata/fimgR.pgm").to_vec();
+/// x.extend(include_bytes!("../tdata/fimg-rainbowA.ppm"));
+/// let images = pnm::decode_all(&x).collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(images.len(), 2);
+/// ```
+pub const fn decode_all(x: &[u8]) -> DecodeIter<'_> {
+    DecodeIter { x }
+}
+
+/// Iterator over the images of a stream, from [`decode_all`].
+#[derive(Debug, Clone)]
+pub struct DecodeIter<'a> {
+    x: &'a [u8],
+}
+
+impl Iterator for DecodeIter<'_> {
+    type Item = decode::Result<DynImage<Vec<u8>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.x = self.x.trim_ascii_start();
+        if self.x.is_empty() {
+            return None;
+        }
+        let image = decode_from(&mut self.x, DecodeOptions::new());
+        if image.is_err() {
+            self.x = &[];
+        }
+        Some(image)
+    }
+}
+
+impl core::iter::FusedIterator for DecodeIter<'_> {}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image into a caller chosen type.
+///
+/// Images with a different channel count are converted.
+///
+/// ```
+/// # use fimg::Image;
+/// let out: Image<Vec<u8>, 3> =
+///     pnm::decode_into(include_bytes!("../tdata/fimg-rainbowR.ppm")).unwrap();
+/// ```
+pub fn decode_into<T: Decode>(x: impl AsRef<[u8]>) -> decode::Result<T> {
+    T::decode(x.as_ref())
+}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image into a buffer you already own, without allocating.
+///
+/// `buf` must hold at least `width * height * channels` bytes, and only that many are written.
+/// Samples are laid out as [`decode()`] would, and the header is returned.
+///
+/// ```
+/// let mut frame = vec![0; 20 * 15 * 3];
+/// let info = pnm::decode_to_slice(include_bytes!("../tdata/fimg-rainbowR.ppm"), &mut frame).unwrap();
+/// assert_eq!(info.channels(), 3);
+/// assert_eq!(frame, include_bytes!("../tdata/fimg-rainbow.imgbuf"));
+/// ```
+pub fn decode_to_slice(x: impl AsRef<[u8]>, buf: &mut [u8]) -> decode::Result<decode::Info> {
+    use decode::{put_samples, raw_samples, samples, Error, Info};
     let mut x = x.as_ref();
-    let magic = decode::magic(&mut x).ok_or(decode::Error::MissingMagic)?;
+    let info = probe_from(&mut x)?;
+    let (w, h) = (info.width().get(), info.height().get());
+    let n = w as usize * h as usize * info.channels() as usize;
+    let got = buf.len();
+    let out = buf
+        .get_mut(..n)
+        .ok_or(Error::BufferSize { expected: n, got })?
+        .as_mut_ptr();
+    // SAFETY: `out` holds `n` bytes.
+    unsafe {
+        match &info {
+            Info::Pnm(header) => match header.magic {
+                pbm::plain::MAGIC => put_samples(pbm::plain::samples(x.iter().copied()), n, out),
+                pbm::raw::MAGIC => pbm::raw::unpack(x, w, h, out, |x| (!x) as u8 * 0xff),
+                pgm::plain::MAGIC | ppm::plain::MAGIC => {
+                    put_samples(samples(x.iter().copied(), header.max.unwrap()), n, out)
+                }
+                _ => put_samples(raw_samples(x, header.max.unwrap()).map(Ok), n, out),
+            },
+            Info::Pam(header) => pam::decode_inner(x, out, *header).map(drop),
+        }
+    }?;
+    Ok(info)
+}
+
+/// Decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image from a [reader](std::io::Read).
+///
+/// The header is parsed as it is read, and the body is read straight into the image.
+///
+/// ```
+/// let f = std::fs::File::open("tdata/fimgR.pgm").unwrap();
+/// let out = pnm::decode_reader(f).unwrap();
+/// assert_eq!((out.width(), out.height()), (20, 15));
+/// ```
+#[cfg(feature = "std")]
+pub fn decode_reader(x: impl std::io::Read) -> decode::Result<DynImage<Vec<u8>>> {
+    decode_from_reader(&mut std::io::BufReader::new(x))
+}
+
+/// Open and decode any [`pgm`], [`ppm`], [`pbm`], [`pam`] image file, reading it with [`decode_reader`].
+///
+/// IO errors become [`Error::Io`](decode::Error::Io).
+///
+/// ```
+/// let out = pnm::open("tdata/fimgR.pgm").unwrap();
+/// assert_eq!((out.width(), out.height()), (20, 15));
+/// ```
+#[cfg(feature = "std")]
+pub fn open(path: impl AsRef<std::path::Path>) -> decode::Result<DynImage<Vec<u8>>> {
+    decode_reader(std::fs::File::open(path)?)
+}
+
+/// Decodes one image from a buffered reader.
+#[cfg(feature = "std")]
+pub(crate) fn decode_from_reader(
+    x: &mut impl std::io::BufRead,
+) -> decode::Result<DynImage<Vec<u8>>> {
+    use decode::{header_from, read_n, rescaled, sample_bytes, samples, Bytes, Error};
+    let magic = decode::magic_from(x).ok_or(Error::MissingMagic)?;
+    if magic == pam::MAGIC {
+        return pam::decode_reader(x);
+    }
+    if !matches!(magic, 1..=6) {
+        return Err(Error::BadMagic(magic));
+    }
+    let header = header_from(x, magic)?;
+    let (w, h) = (header.width, header.height);
+    let n = w.get() as usize * h.get() as usize;
+    let max = header.max.unwrap_or(1);
+    let each = sample_bytes(max);
+    match magic {
+        pbm::raw::MAGIC => Ok(DynImage::Y(pbm::raw::decode_body_into_u8(
+            &read_n(x, w.get().div_ceil(8) as usize * h.get() as usize)?,
+            uninit::Image::new(w, h),
+        )?)),
+        pbm::plain::MAGIC => {
+            let mut bytes = Bytes::new(x);
+            let image = pbm::plain::decode_bytes_into_u8(&mut bytes, uninit::Image::new(w, h));
+            bytes.finish(image).map(DynImage::Y)
+        }
+        // SAFETY: read `n` bytes
+        pgm::raw::MAGIC => Ok(DynImage::Y(unsafe {
+            Image::new(w, h, rescaled(read_n(x, n * each)?, max))
+        })),
+        pgm::plain::MAGIC => {
+            let mut bytes = Bytes::new(x);
+            let image = pgm::plain::decode_samples_into(
+                samples(&mut bytes, header.max.unwrap()),
+                pgm::Uninit::new(w, h),
+            );
+            bytes.finish(image).map(DynImage::Y)
+        }
+        // SAFETY: read `n * 3` bytes
+        ppm::raw::MAGIC => Ok(DynImage::Rgb(unsafe {
+            Image::new(w, h, rescaled(read_n(x, n * 3 * each)?, max))
+        })),
+        _ => {
+            let mut bytes = Bytes::new(x);
+            let image = ppm::plain::decode_samples_into(
+                samples(&mut bytes, header.max.unwrap()),
+                ppm::Uninit::new(w, h),
+            );
+            bytes.finish(image).map(DynImage::Rgb)
+        }
+    }
+}
+
+/// Reads the header of any [`pgm`], [`ppm`], [`pbm`], [`pam`] image, without decoding the body.
+///
+/// ```
+/// let info = pnm::probe(include_bytes!("../tdata/fimg-rainbow.pam")).unwrap();
+/// assert_eq!((info.width().get(), info.height().get(), info.channels()), (20, 15, 3));
+/// ```
+pub fn probe(x: impl AsRef<[u8]>) -> decode::Result<decode::Info> {
+    probe_from(&mut x.as_ref())
+}
+
+/// Which format is this? Only looks at the magic number, so it may not be a valid image.
+///
+/// ```
+/// # use pnm::decode::Format;
+/// assert_eq!(pnm::format(include_bytes!("../tdata/fimg.pam")), Some(Format::Pam));
+/// assert_eq!(pnm::format(b"GIF89a"), None);
+/// ```
+pub fn format(x: impl AsRef<[u8]>) -> Option<decode::Format> {
+    match *x.as_ref() {
+        [b'P', m, ..] => decode::Format::from_magic(m.checked_sub(b'0')?),
+        _ => None,
+    }
+}
+
+/// Reads the header, advancing `x` past it.
+fn probe_from(x: &mut &[u8]) -> decode::Result<decode::Info> {
+    use decode::{Error, Info};
+    let magic = decode::magic(x).ok_or(Error::MissingMagic)?;
+    match magic {
+        pam::MAGIC => Ok(Info::Pam(pam::decode_pam_header(x)?)),
+        1..=6 => Ok(Info::Pnm(decode::decode_header(x, magic)?)),
+        _ => Err(Error::BadMagic(magic)),
+    }
+}
+
+/// Decodes one image, advancing `x` past it.
+pub(crate) fn decode_from(
+    x: &mut &[u8],
+    options: DecodeOptions,
+) -> decode::Result<DynImage<Vec<u8>>> {
+    use decode::{decode_header, sample_bytes, skip, tokens, Budget, Error};
+    let start = *x;
+    let magic = decode::magic(x).ok_or(Error::MissingMagic)?;
+    if magic == pam::MAGIC {
+        return pam::decode_from(x, options.max_ops(options.spend(start.len() - x.len())?));
+    }
+    if !matches!(magic, 1..=6) {
+        return Err(Error::BadMagic(magic));
+    }
+    let header = decode_header(x, magic)?;
+    let left = options.spend(start.len() - x.len())?;
+    let (w, h) = (header.width, header.height);
+    let raw = |n: usize| match n as u64 > left {
+        true => Err(Error::BudgetExceeded),
+        false => Ok(n),
+    };
+    let n = w.get() as usize * h.get() as usize;
+    let each = sample_bytes(header.max.unwrap_or(1));
     match magic {
         pbm::raw::MAGIC => {
-            let header = decode::decode_header(&mut x, pbm::raw::MAGIC)?;
-            Ok(DynImage::Y(pbm::raw::decode_body_into_u8(
-                x,
-                uninit::Image::new(header.width, header.height),
-            )?))
+            let len = raw(w.get().div_ceil(8) as usize * h.get() as usize)?;
+            let image = pbm::raw::decode_body_into_u8(x, uninit::Image::new(w, h))?;
+            skip(x, len);
+            Ok(DynImage::Y(image))
         }
         pbm::plain::MAGIC => {
-            let header = decode::decode_header(&mut x, pbm::plain::MAGIC)?;
-            Ok(DynImage::Y(pbm::plain::decode_body_into_u8(
-                x,
-                uninit::Image::new(header.width, header.height),
-            )?))
+            let mut bytes = Budget::new(x.iter().copied(), left);
+            let image = pbm::plain::decode_bytes_into_u8(&mut bytes, uninit::Image::new(w, h));
+            let used = bytes.pulled();
+            let image = bytes.finish(used, image)?;
+            skip(x, pbm::plain::body_len(x, image.len()));
+            Ok(DynImage::Y(image))
+        }
+        pgm::raw::MAGIC => {
+            let len = raw(n * each)?;
+            let image = pgm::raw::decode_body_into(x, pgm::Uninit::new(w, h), header.max.unwrap())?;
+            skip(x, len);
+            Ok(DynImage::Y(image))
+        }
+        pgm::plain::MAGIC => {
+            let mut bytes = Budget::new(x.iter().copied(), left);
+            let mut tokens = tokens(&mut bytes, header.max.unwrap());
+            let image = pgm::plain::decode_samples_into(tokens.scaled(), pgm::Uninit::new(w, h));
+            // errors point at the bad sample
+            let (at, used) = (tokens.start(), tokens.used());
+            let image = bytes.finish(used, image).inspect_err(|_| skip(x, at))?;
+            skip(x, decode::plain_len(x, image.len()));
+            Ok(DynImage::Y(image))
+        }
+        ppm::raw::MAGIC => {
+            let len = raw(n * 3 * each)?;
+            let image = ppm::raw::decode_body_into(x, ppm::Uninit::new(w, h), header.max.unwrap())?;
+            skip(x, len);
+            Ok(DynImage::Rgb(image))
+        }
+        _ => {
+            let mut bytes = Budget::new(x.iter().copied(), left);
+            let mut tokens = tokens(&mut bytes, header.max.unwrap());
+            let image = ppm::plain::decode_samples_into(tokens.scaled(), ppm::Uninit::new(w, h));
+            // errors point at the bad sample
+            let (at, used) = (tokens.start(), tokens.used());
+            let image = bytes.finish(used, image).inspect_err(|_| skip(x, at))?;
+            skip(x, decode::plain_len(x, image.len()));
+            Ok(DynImage::Rgb(image))
         }
-        pgm::raw::MAGIC => Ok(DynImage::Y(pgm::raw::decode_wo_magic(x)?)),
-        pgm::plain::MAGIC => Ok(DynImage::Y(pgm::plain::decode_wo_magic(x)?)),
-        ppm::raw::MAGIC => Ok(DynImage::Rgb(ppm::raw::decode_wo_magic(x)?)),
-        ppm::plain::MAGIC => Ok(DynImage::Rgb(ppm::plain::decode_wo_magic(x)?)),
-        pam::MAGIC => pam::decode_wo_magic(x),
-        _ => Err(decode::Error::BadMagic(magic)),
     }
 }
 
@@ -71,6 +445,128 @@ pub fn encode(x: impl Encode) -> Vec<u8> {
     x.encode()
 }
 
+/// Encodes an image to one of the [`pgm`] or [`ppm`] portable anymap formats, into a writer.
+///
+/// Please note that this will not produce a [`pam`], use [`pam::encode_to`] for that.
+#[cfg(feature = "std")]
+pub fn encode_to(x: impl Encode, to: &mut impl std::io::Write) -> std::io::Result<()> {
+    x.encode_to(to)
+}
+
+/// Encodes an image with [`encode_to`] into a file at `path`, creating or truncating it. The counterpart to [`open`].
+#[cfg(feature = "std")]
+pub fn write_to_path(x: impl Encode, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut to = std::io::BufWriter::new(std::fs::File::create(path)?);
+    x.encode_to(&mut to)?;
+    to.flush()
+}
+
+/// Encodes an image to one of the [`pgm`] or [`ppm`] portable anymap formats, with a maxval of `max`.
+///
+/// Samples are rescaled from `0..=255` into `0..=max`, rounding to the nearest value.
+/// When `max` is over 255, each sample takes two (big endian) bytes.
+///
+/// ```
+/// # use fimg::Image;
+/// let out = pnm::encode_with_max(Image::<_, 1>::build(3, 1).buf(&[0, 128, 255][..]), 15);
+/// assert_eq!(out, b"P5 3 1 15\n\x00\x08\x0f");
+/// let out = pnm::encode_with_max(Image::<_, 1>::build(3, 1).buf(&[0, 128, 255][..]), 1023);
+/// assert_eq!(out, b"P5 3 1 1023\n\x00\x00\x02\x02\x03\xff");
+/// ```
+///
+/// # Panics
+///
+/// If `max` is 0.
+pub fn encode_with_max(x: impl Encode, max: u16) -> Vec<u8> {
+    x.encode_with_max(max)
+}
+
+/// [`encode_with_max`], but ASCII.
+///
+/// # Panics
+///
+/// If `max` is 0.
+pub fn encode_plain_with_max(x: impl Encode, max: u16) -> String {
+    x.encode_plain_with_max(max)
+}
+
+/// [`encode()`], with a `# comment` after the magic number, such as the software that made it.
+///
+/// Each line of `comment` becomes its own `# ` prefixed line, and [`decode()`] skips them.
+///
+/// ```
+/// # use fimg::Image;
+/// let out = pnm::encode_with_comment(Image::<_, 1>::build(1, 1).buf(&[7][..]), "made by\npnm");
+/// assert_eq!(out, b"P5\n# made by\n# pnm\n1 1 255\n\x07");
+/// ```
+///
+/// # Panics
+///
+/// If `comment` has control characters other than `\n` and `\t`.
+pub fn encode_with_comment(x: impl Encode, comment: &str) -> Vec<u8> {
+    encode::check_comment(comment);
+    x.encode_with_comment(comment)
+}
+
+/// [`encode_with_comment`], but ASCII.
+///
+/// # Panics
+///
+/// If `comment` has control characters other than `\n` and `\t`.
+pub fn encode_plain_with_comment(x: impl Encode, comment: &str) -> String {
+    encode::check_comment(comment);
+    x.encode_plain_with_comment(comment)
+}
+
+/// Encodes an image to one of the [`pgm`] or [`ppm`] portable anymap formats, with a maxval of its largest sample instead of 255.
+///
+/// Samples are stored unchanged, so the file advertises the images true range. Decoding stretches that range back over `0..=255`.
+///
+/// ```
+/// # use fimg::Image;
+/// let out = pnm::encode_tight(Image::<_, 1>::build(2, 1).buf(&[7, 200][..]));
+/// assert_eq!(out, b"P5 2 1 200\n\x07\xc8");
+/// ```
+pub fn encode_tight(x: impl Encode) -> Vec<u8> {
+    x.encode_tight()
+}
+
+/// Encodes an image to one of the [`pgm`] or [`ppm`] portable anymap formats, writing its rows in the order given by `row_order`.
+///
+/// `row_order` must be a permutation of `0..height`. Row `i` of the output is row `row_order[i]` of `x`.
+///
+/// ```
+/// # use fimg::Image;
+/// let x = Image::<_, 1>::build(1, 3).buf(&[1, 2, 3][..]);
+/// let out = pnm::encode_reordered(x, &[2, 0, 1]).unwrap();
+/// assert_eq!(pnm::decode(out).unwrap().bytes(), [3, 1, 2]);
+/// ```
+pub fn encode_reordered(x: impl Encode, row_order: &[u32]) -> encode::Result<Vec<u8>> {
+    x.encode_reordered(row_order)
+}
+
+/// Copies the rows of `x` in the order given by `row_order`.
+fn reorder<const N: usize>(
+    x: Image<&[u8], N>,
+    row_order: &[u32],
+) -> encode::Result<Image<Vec<u8>, N>> {
+    if row_order.len() != x.height() as usize {
+        return Err(encode::Error::BadRowOrder);
+    }
+    let row = x.width() as usize * N;
+    let mut seen = alloc::vec![false; row_order.len()];
+    let mut buf = Vec::with_capacity(x.buffer().len());
+    for &r in row_order {
+        match seen.get_mut(r as usize) {
+            Some(x @ false) => *x = true,
+            _ => return Err(encode::Error::BadRowOrder),
+        }
+        buf.extend_from_slice(&x.buffer()[r as usize * row..][..row]);
+    }
+    Ok(Image::build(x.width(), x.height()).buf(buf))
+}
+
 /// Encodes an image to one of the [`pgm`] or [`ppm`] portable anymap formats.
 ///
 /// Please note that this will not produce a [`pam`], use [`PAM`] for that.
@@ -79,11 +575,87 @@ pub fn encode_plain(x: impl Encode) -> String {
     x.encode_plain()
 }
 
+/// Encodes a raw buffer of `channels` channel pixels, for when the channel count is only known at runtime.
+///
+/// 1 and 3 channel images become a [`pgm`] and [`ppm`] respectively, while 2 and 4 channel images become a [`pam`].
+/// Other channel counts, zero sizes, and buffers not holding `width * height * channels` bytes are an [`encode::Error`].
+///
+/// ```
+/// let out = pnm::encode_raw(include_bytes!("../tdata/fimg-rainbow.imgbuf"), 20, 15, 3).unwrap();
+/// assert_eq!(out, include_bytes!("../tdata/fimg-rainbowR.ppm"));
+/// ```
+pub fn encode_raw(buf: &[u8], width: u32, height: u32, channels: u8) -> encode::Result<Vec<u8>> {
+    use encode::Error;
+    if !(1..=4).contains(&channels) {
+        return Err(Error::UnsupportedDepth(channels));
+    }
+    let w = core::num::NonZeroU32::new(width).ok_or(Error::ZeroWidth)?;
+    let h = core::num::NonZeroU32::new(height).ok_or(Error::ZeroHeight)?;
+    let expected = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|x| x.checked_mul(channels as usize))
+        .ok_or(Error::TooLarge)?;
+    if buf.len() != expected {
+        return Err(Error::BufferSize {
+            expected,
+            got: buf.len(),
+        });
+    }
+    // SAFETY: checked the buffer size
+    Ok(unsafe {
+        match channels {
+            1 => pgm::raw::encode(Image::<_, 1>::new(w, h, buf)),
+            2 => pam::encode(Image::<_, 2>::new(w, h, buf)),
+            3 => ppm::raw::encode(Image::<_, 3>::new(w, h, buf)),
+            _ => pam::encode(Image::<_, 4>::new(w, h, buf)),
+        }
+    })
+}
+
 #[doc(hidden)]
 pub trait Encode {
     fn encode(self) -> Vec<u8>;
+    #[cfg(feature = "std")]
+    fn encode_to(self, to: &mut impl std::io::Write) -> std::io::Result<()>;
     fn encode_plain(self) -> String;
+    fn encode_reordered(self, row_order: &[u32]) -> encode::Result<Vec<u8>>;
+    fn encode_tight(self) -> Vec<u8>;
+    fn encode_with_max(self, max: u16) -> Vec<u8>;
+    fn encode_plain_with_max(self, max: u16) -> String;
+    fn encode_with_comment(self, comment: &str) -> Vec<u8>;
+    fn encode_plain_with_comment(self, comment: &str) -> String;
+}
+
+#[doc(hidden)]
+pub trait Decode: Sized {
+    fn decode(x: &[u8]) -> decode::Result<Self>;
+}
+
+impl Decode for DynImage<Vec<u8>> {
+    fn decode(x: &[u8]) -> decode::Result<Self> {
+        decode(x)
+    }
+}
+
+macro_rules! d {
+    ($n:literal, $variant:ident) => {
+        impl Decode for Image<Vec<u8>, $n> {
+            fn decode(x: &[u8]) -> decode::Result<Self> {
+                Ok(match decode(x)? {
+                    DynImage::$variant(x) => x,
+                    // SAFETY: size unchanged
+                    x => e!(x, |x| unsafe {
+                        <Image<Box<[u8]>, $n>>::from(x.as_ref()).mapped(Vec::from)
+                    }),
+                })
+            }
+        }
+    };
 }
+d![1, Y];
+d![2, Ya];
+d![3, Rgb];
+d![4, Rgba];
 
 macro_rules! x {
     ($mod:ident) => {
@@ -91,9 +663,31 @@ macro_rules! x {
             fn encode(self) -> Vec<u8> {
                 $mod::raw::encode(self)
             }
+            #[cfg(feature = "std")]
+            fn encode_to(self, to: &mut impl std::io::Write) -> std::io::Result<()> {
+                $mod::raw::encode_to(self, to)
+            }
             fn encode_plain(self) -> String {
                 $mod::plain::encode(self)
             }
+            fn encode_tight(self) -> Vec<u8> {
+                $mod::raw::encode_tight(self)
+            }
+            fn encode_with_max(self, max: u16) -> Vec<u8> {
+                $mod::raw::encode_with_max(self, max)
+            }
+            fn encode_plain_with_max(self, max: u16) -> String {
+                $mod::plain::encode_with_max(self, max)
+            }
+            fn encode_with_comment(self, comment: &str) -> Vec<u8> {
+                $mod::raw::encode_commented(self.as_ref(), Some(comment))
+            }
+            fn encode_plain_with_comment(self, comment: &str) -> String {
+                $mod::plain::encode_commented(self.as_ref(), Some(comment))
+            }
+            fn encode_reordered(self, row_order: &[u32]) -> encode::Result<Vec<u8>> {
+                reorder(self.as_ref(), row_order).map(Encode::encode)
+            }
         }
     };
     (t $mod:ident, $n:literal) => {
@@ -101,9 +695,46 @@ macro_rules! x {
             fn encode(self) -> Vec<u8> {
                 $mod::raw::encode(<Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()))
             }
+            #[cfg(feature = "std")]
+            fn encode_to(self, to: &mut impl std::io::Write) -> std::io::Result<()> {
+                $mod::raw::encode_to(
+                    <Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()),
+                    to,
+                )
+            }
             fn encode_plain(self) -> String {
                 $mod::plain::encode(<Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()))
             }
+            fn encode_tight(self) -> Vec<u8> {
+                $mod::raw::encode_tight(<Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()))
+            }
+            fn encode_with_max(self, max: u16) -> Vec<u8> {
+                $mod::raw::encode_with_max(
+                    <Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()),
+                    max,
+                )
+            }
+            fn encode_plain_with_max(self, max: u16) -> String {
+                $mod::plain::encode_with_max(
+                    <Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()),
+                    max,
+                )
+            }
+            fn encode_with_comment(self, comment: &str) -> Vec<u8> {
+                $mod::raw::encode_commented(
+                    <Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()).as_ref(),
+                    Some(comment),
+                )
+            }
+            fn encode_plain_with_comment(self, comment: &str) -> String {
+                $mod::plain::encode_commented(
+                    <Image<Box<[u8]>, { $mod::CHANNELS }>>::from(self.as_ref()).as_ref(),
+                    Some(comment),
+                )
+            }
+            fn encode_reordered(self, row_order: &[u32]) -> encode::Result<Vec<u8>> {
+                reorder(self.as_ref(), row_order).map(Encode::encode)
+            }
         }
     };
 }
@@ -127,7 +758,597 @@ impl<T: AsRef<[u8]>> Encode for DynImage<T> {
     fn encode(self) -> Vec<u8> {
         e!(self, |x| encode(x))
     }
+    #[cfg(feature = "std")]
+    fn encode_to(self, to: &mut impl std::io::Write) -> std::io::Result<()> {
+        e!(self, |x| encode_to(x, to))
+    }
     fn encode_plain(self) -> String {
         e!(self, |x| encode_plain(x))
     }
+    fn encode_reordered(self, row_order: &[u32]) -> encode::Result<Vec<u8>> {
+        e!(self, |x| encode_reordered(x, row_order))
+    }
+    fn encode_tight(self) -> Vec<u8> {
+        e!(self, |x| encode_tight(x))
+    }
+    fn encode_with_max(self, max: u16) -> Vec<u8> {
+        e!(self, |x| encode_with_max(x, max))
+    }
+    fn encode_plain_with_max(self, max: u16) -> String {
+        e!(self, |x| encode_plain_with_max(x, max))
+    }
+    fn encode_with_comment(self, comment: &str) -> Vec<u8> {
+        e!(self, |x| x.encode_with_comment(comment))
+    }
+    fn encode_plain_with_comment(self, comment: &str) -> String {
+        e!(self, |x| x.encode_plain_with_comment(comment))
+    }
+}
+
+/// Every 20x15 image in `tdata`, by file name.
+#[cfg(test)]
+const FIXTURES: &[(&str, &[u8])] = {
+    macro_rules! fixtures {
+        ($($name:literal),+ $(,)?) => {
+            &[$(($name, include_bytes!(concat!("../tdata/", $name)))),+]
+        };
+    }
+    fixtures![
+        "fimgA.pbm",
+        "fimgR.pbm",
+        "fimgA.pgm",
+        "fimgR.pgm",
+        "fimg-rainbowA.ppm",
+        "fimg-rainbowR.ppm",
+        "fimg.pam",
+        "fimg-gray.pam",
+        "fimg-transparent.pam",
+        "fimg-rainbow.pam",
+        "fimg-rgb.pam",
+        "fimg-rainbow-transparent.pam",
+    ]
+};
+
+/// The [`Format`](decode::Format) a fixture is stored in, going by its name.
+#[cfg(test)]
+fn fixture_format(name: &str) -> decode::Format {
+    use decode::Format;
+    let plain = name.contains("A.");
+    match name.rsplit_once('.').unwrap().1 {
+        "pbm" if plain => Format::PbmPlain,
+        "pbm" => Format::PbmRaw,
+        "pgm" if plain => Format::PgmPlain,
+        "pgm" => Format::PgmRaw,
+        "ppm" if plain => Format::PpmPlain,
+        "ppm" => Format::PpmRaw,
+        _ => Format::Pam,
+    }
+}
+
+#[test]
+fn test_decode_at_offset() {
+    let mut x = b"\x00\x01junk".to_vec();
+    x.extend(include_bytes!("../tdata/fimgA.pgm"));
+    x.extend(b"more junk");
+    let (out, at) = decode_at_offset(&x, 6).unwrap();
+    assert_eq!(&**out.buffer(), include_bytes!("../tdata/fimg-gray.imgbuf"));
+    assert_eq!(x[at..].trim_ascii_start(), b"more junk");
+
+    assert!(matches!(
+        decode_at_offset(&x, x.len() + 1),
+        Err(decode::Error::OffsetOutOfBounds { .. })
+    ));
+}
+
+#[test]
+fn test_decode_into() {
+    let x = include_bytes!("../tdata/fimg-rainbowR.ppm");
+    let rgb = include_bytes!("../tdata/fimg-rainbow.imgbuf");
+    assert_eq!(
+        &**decode_into::<DynImage<Vec<u8>>>(x).unwrap().buffer(),
+        rgb
+    );
+    assert_eq!(
+        &**decode_into::<Image<Vec<u8>, 3>>(x).unwrap().buffer(),
+        rgb
+    );
+    assert_eq!(decode_into::<Image<Vec<u8>, 1>>(x).unwrap().len(), 20 * 15);
+    assert_eq!(
+        decode_into::<Image<Vec<u8>, 2>>(x).unwrap().len(),
+        20 * 15 * 2
+    );
+    let rgba = decode_into::<Image<Vec<u8>, 4>>(x).unwrap();
+    assert!(rgba
+        .chunked()
+        .zip(rgb.chunks_exact(3))
+        .all(|(&[r, g, b, a], x)| [r, g, b] == x && a == 255));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_decode_reader() {
+    for &(_, x) in FIXTURES {
+        assert_eq!(
+            decode_reader(std::io::Cursor::new(x)).unwrap(),
+            decode(x).unwrap()
+        );
+        assert!(matches!(
+            decode_reader(std::io::Cursor::new(&x[..x.len() - 10])),
+            Err(decode::Error::MissingData)
+        ));
+    }
+    assert!(matches!(
+        decode_reader(std::io::Cursor::new(b"P9 1 1 255\n\0")),
+        Err(decode::Error::BadMagic(9))
+    ));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_encode_to() {
+    let x = decode(include_bytes!("../tdata/fimg-rainbowR.ppm")).unwrap();
+    let mut out = std::io::BufWriter::new(vec![]);
+    encode_to(x.as_ref(), &mut out).unwrap();
+    assert_eq!(out.into_inner().unwrap(), encode(x));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_encode_to_matches_encode_into() {
+    fn into(size: usize, f: impl FnOnce(*mut u8) -> usize) -> Vec<u8> {
+        let mut y = Vec::with_capacity(size);
+        // SAFETY: size() is an upper bound on the output.
+        unsafe {
+            let n = f(y.as_mut_ptr());
+            y.set_len(n);
+        }
+        y
+    }
+    fn to(f: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>) -> Vec<u8> {
+        let mut y = vec![];
+        f(&mut y).unwrap();
+        y
+    }
+    let grey = Image::<_, 1>::build(20, 15).buf(&include_bytes!("../tdata/fimg-gray.imgbuf")[..]);
+    let rgb = Image::<_, 3>::build(20, 15).buf(&include_bytes!("../tdata/fimg-rainbow.imgbuf")[..]);
+    let bits = include_bytes!("../tdata/fimg.imgbuf")
+        .iter()
+        .map(|&x| x <= 128)
+        .collect::<Vec<_>>();
+    let bits = Image::<_, 1>::build(20, 15).buf(&bits[..]);
+    macro_rules! same {
+        ($m:ident, $x:expr) => {
+            assert_eq!(
+                to(|y| $m::plain::encode_to($x.as_ref(), y)),
+                into($m::plain::size($x.as_ref()), |o| unsafe {
+                    $m::plain::encode_into($x.as_ref(), o)
+                })
+            );
+            assert_eq!(
+                to(|y| $m::raw::encode_to($x.as_ref(), y)),
+                into($m::raw::size($x.as_ref()), |o| unsafe {
+                    $m::raw::encode_into($x.as_ref(), o)
+                })
+            );
+        };
+    }
+    same!(pbm, bits);
+    same!(pgm, grey);
+    same!(ppm, rgb);
+    assert_eq!(
+        to(|y| pam::encode_to(rgb.as_ref(), y)),
+        into(pam::size(rgb.buffer()), |o| unsafe {
+            pam::PAM::encode_into(rgb.as_ref(), o)
+        })
+    );
+    assert_eq!(
+        to(|y| pam::encode_bitmap_to(bits.as_ref(), y)),
+        into(pam::size(bits.buffer()), |o| unsafe {
+            pam::PAMBit::encode_into(bits.as_ref(), o)
+        })
+    );
+}
+
+#[test]
+fn test_encode_raw() {
+    for channels in [0, 5, 255] {
+        assert!(matches!(
+            encode_raw(&[0; 20], 2, 2, channels),
+            Err(encode::Error::UnsupportedDepth(x)) if x == channels
+        ));
+    }
+    for channels in 1..=4 {
+        let buf = (0..2 * 3 * channels).collect::<Vec<u8>>();
+        let out = encode_raw(&buf, 2, 3, channels).unwrap();
+        assert_eq!(decode(out).unwrap().bytes(), buf);
+    }
+    assert!(matches!(
+        encode_raw(&[0; 5], 2, 3, 1),
+        Err(encode::Error::BufferSize {
+            expected: 6,
+            got: 5
+        })
+    ));
+    assert_eq!(encode_raw(&[], 0, 3, 1), Err(encode::Error::ZeroWidth));
+    assert_eq!(encode_raw(&[], 3, 0, 1), Err(encode::Error::ZeroHeight));
+}
+
+#[test]
+fn test_encode_reordered() {
+    let x = Image::<_, 3>::build(20, 15).buf(&include_bytes!("../tdata/fimg-rainbow.imgbuf")[..]);
+    let flipped = x
+        .buffer()
+        .chunks_exact(20 * 3)
+        .rev()
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+    let out = encode_reordered(x, &(0..15).rev().collect::<Vec<_>>()).unwrap();
+    assert_eq!(decode(out).unwrap().bytes(), flipped);
+    for order in [&[0, 1][..], &[0, 0, 1], &[0, 1, 3]] {
+        assert!(matches!(
+            encode_reordered(Image::<_, 1>::build(1, 3).buf(&[1, 2, 3][..]), order),
+            Err(encode::Error::BadRowOrder)
+        ));
+    }
+}
+
+#[test]
+fn test_decode_located() {
+    use decode::Error;
+    for (x, offset) in [
+        (&b"GIF89a"[..], 0),
+        (b"P9 1 1 255\n", 0),
+        (b"P2 2 1 255\n1", 12),
+        (b"P2 3 1 255\n1 x 3", 13),
+        (b"P3 1 1 255\n1 2 999", 15),
+        (b"P2 2 1 255\n1   0270", 15),
+        (b"P5 1 1 2x5\n", 8),
+        (b"P7\nWIDTH 1\nHEIGHT x\nDEPTH 1\n", 11),
+        (b"P5 2 1 255\n\x01", 12),
+    ] {
+        let e = decode_located(x).unwrap_err();
+        assert_eq!(e.offset, offset, "{e}");
+    }
+    assert!(matches!(
+        decode_located(b"P5 1 1 2x5\n").unwrap_err().error,
+        Error::NotDigit('x')
+    ));
+}
+
+#[test]
+fn test_encode_tight() {
+    let buf = (0..20 * 15 * 3)
+        .map(|x| (x % 201) as u8)
+        .collect::<Vec<_>>();
+    let out = encode_tight(Image::<_, 3>::build(20, 15).buf(&buf[..]));
+    assert!(out.starts_with(b"P6 20 15 200\n"));
+    assert_eq!(probe(&out).unwrap().max(), 200);
+    let stretched = buf
+        .iter()
+        .map(|&x| decode::rescale(x.into(), 200))
+        .collect::<Vec<_>>();
+    assert_eq!(decode(&out).unwrap().bytes(), stretched);
+}
+
+#[test]
+fn test_decode_to_slice() {
+    let mut buf = vec![0xaa; 20 * 15 * 4 + 1];
+    for &(_, x) in FIXTURES {
+        let expected = decode(x).unwrap();
+        let info = decode_to_slice(x, &mut buf).unwrap();
+        assert_eq!(info.channels() as usize, expected.bytes().len() / 300);
+        assert_eq!(&buf[..expected.bytes().len()], expected.bytes());
+        assert!(matches!(
+            decode_to_slice(x, &mut buf[..expected.bytes().len() - 1]),
+            Err(decode::Error::BufferSize { .. })
+        ));
+    }
+    assert_eq!(buf[20 * 15 * 4], 0xaa);
+    let x = include_bytes!("../tdata/fimgR.pgm");
+    assert!(matches!(
+        decode_to_slice(&x[..x.len() - 1], &mut buf),
+        Err(decode::Error::MissingData)
+    ));
+}
+
+#[test]
+fn test_encode_with_max() {
+    let x = Image::<_, 3>::build(20, 15).buf(&include_bytes!("../tdata/fimg-rainbow.imgbuf")[..]);
+    for max in [1, 15, 100, 254, 255] {
+        let raw = encode_with_max(x, max);
+        let plain = encode_plain_with_max(x, max);
+        assert_eq!(probe(&raw).unwrap().max(), max);
+        // both are rescaled back to 0..=255, the same way
+        let out = decode(&raw).unwrap();
+        assert_eq!(out.bytes(), decode(&plain).unwrap().bytes());
+        #[cfg(feature = "std")]
+        assert_eq!(decode_reader(&raw[..]).unwrap().bytes(), out.bytes());
+        for (&a, &b) in out.bytes().iter().zip(x.buffer().iter()) {
+            assert!(a.abs_diff(b) as u32 * max as u32 <= 255, "{a} {b} {max}");
+        }
+    }
+    assert_eq!(
+        decode(b"P5 3 1 15\n\x00\x08\x0f").unwrap().bytes(),
+        [0, 136, 255]
+    );
+    assert!(matches!(
+        decode(b"P5 1 1 0\n\x00"),
+        Err(decode::Error::ZeroMax)
+    ));
+    // samples of 16 bit maxvals are two big endian bytes
+    let x = b"P5 3 1 65535\n\x00\x00\x80\x00\xff\xff";
+    assert_eq!(probe(x).unwrap().max(), 65535);
+    assert_eq!(decode(x).unwrap().bytes(), [0, 128, 255]);
+    #[cfg(feature = "std")]
+    assert_eq!(decode_reader(&x[..]).unwrap().bytes(), [0, 128, 255]);
+    let mut out = [0; 3];
+    decode_to_slice(x, &mut out).unwrap();
+    assert_eq!(out, [0, 128, 255]);
+    // and encode the same way
+    let x = Image::<_, 3>::build(20, 15).buf(&include_bytes!("../tdata/fimg-rainbow.imgbuf")[..]);
+    for max in [256, 1023, 65535] {
+        let raw = encode_with_max(x, max);
+        assert_eq!(probe(&raw).unwrap().max(), max);
+        // two bytes a sample, after the header line
+        let header = raw.iter().position(|&x| x == b'\n').unwrap() + 1;
+        assert_eq!(raw.len() - header, 20 * 15 * 3 * 2);
+        assert_eq!(decode(&raw).unwrap().bytes(), *x.buffer());
+        assert_eq!(
+            decode(encode_plain_with_max(x, max)).unwrap().bytes(),
+            *x.buffer()
+        );
+    }
+}
+
+#[test]
+fn test_format() {
+    for &(name, x) in FIXTURES {
+        let expected = fixture_format(name);
+        assert_eq!(format(x), Some(expected));
+        assert_eq!(Some(expected.magic()), decode::magic(&mut &x[..]));
+    }
+    for x in [&b""[..], b"P", b"P0", b"P8", b"Pf", b"p6", b"\x89PNG"] {
+        assert_eq!(format(x), None);
+    }
+}
+
+#[test]
+fn test_header_new() {
+    use decode::{Format, Header};
+    let x = Header::new(Format::PgmRaw, 20, 15).with_max(15);
+    assert_eq!(
+        (x.magic, x.width.get(), x.height.get(), x.max),
+        (5, 20, 15, Some(15))
+    );
+    assert_eq!(Header::new(Format::PbmPlain, 1, 1).with_max(15).max, None);
+    assert_eq!(Header::default().magic, 6);
+}
+
+#[test]
+fn test_probe() {
+    for &(name, x) in FIXTURES {
+        let info = probe(x).unwrap();
+        assert_eq!(
+            (info.magic(), info.width().get(), info.height().get()),
+            (fixture_format(name).magic(), 20, 15)
+        );
+        let channels = decode(x).unwrap().bytes().len() / (20 * 15);
+        let max = if name.ends_with(".pbm") { 1 } else { 255 };
+        assert_eq!((info.channels() as usize, info.max()), (channels, max));
+    }
+    assert!(matches!(probe(b"P8 1 1"), Err(decode::Error::BadMagic(8))));
+}
+
+#[test]
+fn test_header_at_eof() {
+    let info = probe(include_bytes!("../tdata/header-only.pgm")).unwrap();
+    assert_eq!((info.width().get(), info.height().get()), (2, 1));
+    assert_eq!(info.max(), 255);
+    assert!(matches!(
+        decode(include_bytes!("../tdata/header-only.pgm")),
+        Err(decode::Error::MissingData)
+    ));
+}
+
+#[test]
+fn test_decode_all() {
+    let mut x = vec![];
+    for &(_, image) in FIXTURES {
+        x.extend(image);
+        x.extend(b"\n");
+    }
+    let out = decode_all(&x).collect::<decode::Result<Vec<_>>>().unwrap();
+    assert_eq!(out.len(), FIXTURES.len());
+    for (out, &(_, image)) in out.into_iter().zip(FIXTURES) {
+        assert_eq!(out, decode(image).unwrap());
+    }
+
+    x.extend(&include_bytes!("../tdata/fimgR.pgm")[..50]);
+    let mut it = decode_all(&x).skip(FIXTURES.len());
+    assert!(matches!(it.next(), Some(Err(decode::Error::MissingData))));
+    assert!(it.next().is_none());
+    assert_eq!(decode_all(b" \n\t").count(), 0);
+}
+
+#[test]
+fn test_budget() {
+    let mut x = b"P2 2 2 255\n".to_vec();
+    x.extend(std::iter::repeat_n(b' ', 1 << 20));
+    x.extend(b"1 2 3 4\n");
+    assert!(decode(&x).is_ok());
+    let budget = DecodeOptions::new().max_ops(1000);
+    assert!(matches!(
+        decode_with(&x, budget),
+        Err(decode::Error::BudgetExceeded)
+    ));
+    assert!(decode_with(b"P2 2 2 255\n1 2 3 4\n", budget).is_ok());
+    assert!(matches!(
+        decode_with(
+            include_bytes!("../tdata/fimgR.pgm"),
+            DecodeOptions::new().max_ops(100)
+        ),
+        Err(decode::Error::BudgetExceeded)
+    ));
+    assert!(matches!(
+        decode_with(
+            include_bytes!("../tdata/fimg-gray.pam"),
+            DecodeOptions::new().max_ops(366)
+        ),
+        Err(decode::Error::BudgetExceeded)
+    ));
+    assert!(decode_with(
+        include_bytes!("../tdata/fimg-gray.pam"),
+        DecodeOptions::new().max_ops(367)
+    )
+    .is_ok());
+    let over = |x: &[u8], ops| {
+        matches!(
+            decode_with(x, DecodeOptions::new().max_ops(ops)),
+            Err(decode::Error::BudgetExceeded)
+        )
+    };
+    // a budget one byte short of the image runs out, however the input ends
+    for x in [
+        &b"P2 2 1 255\n1 2"[..],
+        b"P2 3 1 255\n1 2 3",
+        b"P2 2 1 255\n1 2\n\n\n\n",
+    ] {
+        let len = x.trim_ascii_end().len() as u64;
+        for ops in len - 3..len {
+            assert!(over(x, ops), "{ops}");
+        }
+        // the byte peeked past the image is free
+        assert!(decode_with(x, DecodeOptions::new().max_ops(len)).is_ok());
+    }
+    assert!(over(b"P1 2 1\n0 1", 9));
+    assert!(decode_with(b"P1 2 1\n0 1\n\n", DecodeOptions::new().max_ops(10)).is_ok());
+}
+
+#[test]
+fn test_maxval_presence() {
+    /// First `n` whitespace separated tokens, and the rest.
+    fn tokens(x: &[u8], n: usize) -> (Vec<&[u8]>, &[u8]) {
+        let mut x = x;
+        let mut out = vec![];
+        for _ in 0..n {
+            let end = x.iter().position(u8::is_ascii_whitespace).unwrap();
+            out.push(&x[..end]);
+            // exactly one whitespace character follows the header
+            x = &x[end + 1..];
+        }
+        (out, x)
+    }
+    let bits = (0..9 * 2).map(|x| x % 3 == 0).collect::<Vec<_>>();
+    let bytes = (0..9 * 2 * 3).collect::<Vec<u8>>();
+    let bitmap = || Image::<_, 1>::build(9, 2).buf(&bits[..]);
+    let y = || Image::<_, 1>::build(9, 2).buf(&bytes[..18]);
+    let rgb = || Image::<_, 3>::build(9, 2).buf(&bytes[..]);
+
+    // PBM never has a maxval: the body starts right after the height.
+    for (out, magic) in [
+        (pbm::raw::encode(bitmap()), &b"P4"[..]),
+        (pbm::plain::encode(bitmap()).into_bytes(), b"P1"),
+    ] {
+        let (header, body) = tokens(&out, 3);
+        assert_eq!(header, [magic, b"9", b"2"]);
+        assert_eq!(probe(&out).unwrap().max(), 1);
+        assert!(!body.starts_with(b"255"));
+        assert_eq!(
+            decode(&out).unwrap().bytes(),
+            bits.iter().map(|&x| !x as u8 * 0xff).collect::<Vec<_>>()
+        );
+    }
+
+    // PGM and PPM always have one.
+    for (out, magic, data) in [
+        (pgm::raw::encode(y()), &b"P5"[..], &bytes[..18]),
+        (pgm::plain::encode(y()).into_bytes(), b"P2", &bytes[..18]),
+        (ppm::raw::encode(rgb()), b"P6", &bytes[..]),
+        (ppm::plain::encode(rgb()).into_bytes(), b"P3", &bytes[..]),
+    ] {
+        let (header, _) = tokens(&out, 4);
+        assert_eq!(header, [magic, b"9", b"2", b"255"]);
+        assert_eq!(probe(&out).unwrap().max(), 255);
+        assert_eq!(decode(&out).unwrap().bytes(), data);
+    }
+
+    // And so does PAM.
+    for out in [pam::encode(y()), pam::encode(rgb())] {
+        assert_eq!(
+            out.split(|&x| x == b'\n')
+                .filter(|x| x.starts_with(b"MAXVAL"))
+                .collect::<Vec<_>>(),
+            [b"MAXVAL 255"]
+        );
+        assert_eq!(probe(&out).unwrap().max(), 255);
+    }
+}
+
+#[test]
+fn test_encode_with_comment() {
+    let x = Image::<_, 3>::build(20, 15).buf(&include_bytes!("../tdata/fimg-rainbow.imgbuf")[..]);
+    let out = encode_with_comment(x, "pnm\n\nsaid hi ✓");
+    assert!(out.starts_with(b"P6\n# pnm\n# \n# said hi \xe2\x9c\x93\n20 15 255\n"));
+    assert_eq!(decode(&out).unwrap().bytes(), &x.buffer()[..]);
+    let out = encode_plain_with_comment(x, "pnm");
+    assert!(out.starts_with("P3\n# pnm\n20 15 255\n"));
+    assert_eq!(decode(&out).unwrap().bytes(), &x.buffer()[..]);
+    let out = encode_plain_with_comment(x, "made\twith pnm");
+    assert!(out.starts_with("P3\n# made\twith pnm\n20 15 255\n"));
+    assert_eq!(decode(&out).unwrap().bytes(), &x.buffer()[..]);
+    assert!(std::panic::catch_unwind(|| encode_with_comment(x, "\r")).is_err());
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_open() {
+    for &(name, x) in FIXTURES {
+        assert_eq!(
+            open(format!("tdata/{name}")).unwrap().bytes(),
+            decode(x).unwrap().bytes(),
+        );
+    }
+    assert!(matches!(
+        open("tdata/missing.pgm"),
+        Err(decode::Error::Io(std::io::ErrorKind::NotFound))
+    ));
+
+    let x = open("tdata/fimg-rainbowR.ppm").unwrap();
+    let path = std::env::temp_dir().join("pnm-test-write-to-path.ppm");
+    write_to_path(x.clone(), &path).unwrap();
+    assert_eq!(open(&path).unwrap().bytes(), x.bytes());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_decode_borrowed() {
+    use decode::Format;
+    for &(name, data) in FIXTURES {
+        // plain bodies are parsed, and bitmaps are expanded
+        let borrowed = matches!(
+            fixture_format(name),
+            Format::PgmRaw | Format::PpmRaw | Format::Pam
+        ) && name != "fimg.pam";
+        let out = decode_borrowed(data).unwrap();
+        let buf = e!(&out, |x| x.buffer());
+        match buf {
+            Cow::Borrowed(x) => {
+                assert!(borrowed);
+                assert!(data.as_ptr_range().contains(&x.as_ptr()));
+            }
+            Cow::Owned(_) => assert!(!borrowed),
+        }
+        assert_eq!(&**buf, decode(data).unwrap().bytes());
+    }
+    let x = include_bytes!("../tdata/fimgR.pgm");
+    assert!(matches!(
+        decode_borrowed(&x[..x.len() - 1]),
+        Err(decode::Error::MissingData)
+    ));
+    // other maxvals need rescaling
+    let out = decode_borrowed(b"P5 3 1 15\n\x00\x08\x0f").unwrap();
+    let buf = e!(&out, |x| x.buffer());
+    assert!(matches!(buf, Cow::Owned(_)));
+    assert_eq!(&**buf, [0, 136, 255]);
 }
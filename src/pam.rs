@@ -1,12 +1,19 @@
 //! [Portable Arbitrary Format](https://en.wikipedia.org/wiki/Netpbm#PAM_graphics_format) RGB (no alpha) image encoding and decoding.
 pub type Input<'a> = Image<&'a [u8], 3>;
 pub type Uninit = fimg::uninit::Image<u8, 3>;
-use std::num::NonZeroU32;
-
-use crate::decode::{read_til, Error, Read, Result};
-use crate::encode::{encodeu32, P};
+use core::num::NonZeroU32;
+
+#[cfg(feature = "std")]
+use crate::decode::read_n;
+use crate::decode::{read_til, rescale, DecodeOptions, Error, Result};
+#[cfg(feature = "std")]
+use crate::encode::Io;
+use crate::encode::{done, encodeu32, Ptr, Sink, P};
+use alloc::vec::Vec;
 use atools::Join;
 use fimg::{DynImage, Image};
+#[cfg(feature = "std")]
+use std::io::Write;
 
 pub const MAGIC: u8 = 7;
 
@@ -23,14 +30,70 @@ pub fn encode(x: impl PAM) -> Vec<u8> {
     x.encode()
 }
 
+/// Encode this <code>[Image]<[u8], N></code> to a [PAM](https://en.wikipedia.org/wiki/Netpbm#PAM_graphics_format) Raw (binary) Image, into a writer.
+#[cfg(feature = "std")]
+pub fn encode_to(x: impl PAM, to: &mut impl Write) -> std::io::Result<()> {
+    x.encode_to(to)
+}
+
 /// Encode this <code>[Image]<[bool], N></code> to a [PAM](https://en.wikipedia.org/wiki/Netpbm#PAM_graphics_format) Raw (binary) Image.
 pub fn encode_bitmap(x: impl PAMBit) -> Vec<u8> {
     x.encode_bitmap()
 }
 
+/// Encode this <code>[Image]<[bool], N></code> to a [PAM](https://en.wikipedia.org/wiki/Netpbm#PAM_graphics_format) Raw (binary) Image, into a writer.
+#[cfg(feature = "std")]
+pub fn encode_bitmap_to(x: impl PAMBit, to: &mut impl Write) -> std::io::Result<()> {
+    x.encode_bitmap_to(to)
+}
+
+/// Encode this <code>[Image]<[u8], N></code>, of any channel count, to a [PAM](https://en.wikipedia.org/wiki/Netpbm#PAM_graphics_format) Raw (binary) Image with a `TUPLTYPE` of your choosing.
+///
+/// ```
+/// # use pnm::pam;
+/// # use fimg::Image;
+/// let out = pam::encode_custom(Image::<_, 5>::build(1, 1).buf(&[1, 2, 3, 4, 5][..]), "MULTISPECTRAL").unwrap();
+/// let (header, buf) = pam::decode_raw(&out).unwrap();
+/// assert_eq!(header.depth, 5);
+/// assert_eq!(buf, [1, 2, 3, 4, 5]);
+/// ```
+///
+/// A `tupltype` longer than [`TypeName::MAX`] bytes, or with control characters (such as a newline), is an error, as it would not decode.
+///
+/// # Panics
+///
+/// If `N` is not in `1..=255`.
+pub fn encode_custom<T: AsRef<[u8]>, const N: usize>(
+    x: Image<T, N>,
+    tupltype: &str,
+) -> crate::encode::Result<Vec<u8>> {
+    use crate::encode::Error;
+    let depth = u8::try_from(N)
+        .ok()
+        .filter(|&x| x != 0)
+        .expect("depth out of range");
+    if tupltype.len() > TypeName::MAX {
+        return Err(Error::LongTupltype);
+    }
+    if tupltype.chars().any(char::is_control) {
+        return Err(Error::BadTupltype);
+    }
+    let x = x.as_ref();
+    let mut y = Vec::new();
+    done(write(
+        (x.buffer(), (x.width(), x.height())),
+        tupltype.as_bytes(),
+        depth,
+        &mut y,
+    ));
+    Ok(y)
+}
+
 #[doc(hidden)]
 pub trait PAM {
     fn encode(self) -> Vec<u8>;
+    #[cfg(feature = "std")]
+    fn encode_to(self, to: &mut impl Write) -> std::io::Result<()>;
     #[doc = include_str!("encode_into.md")]
     unsafe fn encode_into(x: Self, out: *mut u8) -> usize;
 }
@@ -38,123 +101,162 @@ pub trait PAM {
 #[doc(hidden)]
 pub trait PAMBit {
     fn encode_bitmap(self) -> Vec<u8>;
+    #[cfg(feature = "std")]
+    fn encode_bitmap_to(self, to: &mut impl Write) -> std::io::Result<()>;
     #[doc = include_str!("encode_into.md")]
     unsafe fn encode_into(x: Self, out: *mut u8) -> usize;
 }
 
-impl<T: AsRef<[u8]>> PAM for Image<T, 1> {
-    fn encode(self) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(self.bytes()));
-        let n = unsafe { PAM::encode_into(self.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
-        y
-    }
-
-    unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
-        encode_into((x.bytes(), (x.width(), x.height())), out, b"GRAYSCALE", 1)
-    }
-}
-
 impl<T: AsRef<[bool]>> PAMBit for Image<T, 1> {
     fn encode_bitmap(self) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(self.as_ref().buffer()));
-        let n = unsafe { PAMBit::encode_into(self.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        let mut y = Vec::new();
+        done(write(
+            (bits(self.buffer().as_ref()), (self.width(), self.height())),
+            b"BLACKANDWHITE",
+            1,
+            &mut y,
+        ));
         y
     }
 
-    unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
-        let b = x.buffer().as_ref();
-        let b = std::slice::from_raw_parts(b.as_ptr() as *mut u8, b.len());
-        encode_into((b, (x.width(), x.height())), out, b"BLACKANDWHITE", 1)
-    }
-}
-
-impl<T: AsRef<[u8]>> PAM for Image<T, 2> {
-    fn encode(self) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(self.bytes()));
-        let n = unsafe { PAM::encode_into(self.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
-        y
+    #[cfg(feature = "std")]
+    fn encode_bitmap_to(self, to: &mut impl Write) -> std::io::Result<()> {
+        write(
+            (bits(self.buffer().as_ref()), (self.width(), self.height())),
+            b"BLACKANDWHITE",
+            1,
+            &mut Io(to),
+        )
     }
 
     unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
-        encode_into(
-            (x.bytes(), (x.width(), x.height())),
-            out,
-            b"GRAYSCALE_ALPHA",
-            2,
-        )
+        let mut o = Ptr::new(out);
+        done(write(
+            (bits(x.buffer().as_ref()), (x.width(), x.height())),
+            b"BLACKANDWHITE",
+            1,
+            &mut o,
+        ));
+        o.written()
     }
 }
 
-impl<T: AsRef<[u8]>> PAM for Image<T, 3> {
-    fn encode(self) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(self.bytes()));
-        let n = unsafe { PAM::encode_into(self.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
-        y
-    }
-
-    unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
-        encode_into((x.bytes(), (x.width(), x.height())), out, b"RGB", 3)
-    }
+const fn bits(x: &[bool]) -> &[u8] {
+    // SAFETY: bools are bytes of 0 or 1
+    unsafe { core::slice::from_raw_parts(x.as_ptr() as *const u8, x.len()) }
 }
 
-impl<T: AsRef<[u8]>> PAM for Image<T, 4> {
-    fn encode(self) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(self.bytes()));
-        let n = unsafe { PAM::encode_into(self.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
-        y
-    }
+macro_rules! pam {
+    ($n:literal, $tupltype:literal) => {
+        impl<T: AsRef<[u8]>> PAM for Image<T, $n> {
+            fn encode(self) -> Vec<u8> {
+                let mut y = Vec::new();
+                done(write(
+                    (self.bytes(), (self.width(), self.height())),
+                    $tupltype,
+                    $n,
+                    &mut y,
+                ));
+                y
+            }
 
-    unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
-        encode_into((x.bytes(), (x.width(), x.height())), out, b"RGB_ALPHA", 2)
-    }
+            #[cfg(feature = "std")]
+            fn encode_to(self, to: &mut impl Write) -> std::io::Result<()> {
+                write(
+                    (self.bytes(), (self.width(), self.height())),
+                    $tupltype,
+                    $n,
+                    &mut Io(to),
+                )
+            }
+
+            unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
+                let mut o = Ptr::new(out);
+                done(write(
+                    (x.bytes(), (x.width(), x.height())),
+                    $tupltype,
+                    $n,
+                    &mut o,
+                ));
+                o.written()
+            }
+        }
+    };
 }
+pam!(1, b"GRAYSCALE");
+pam!(2, b"GRAYSCALE_ALPHA");
+pam!(3, b"RGB");
+pam!(4, b"RGB_ALPHA");
 
 impl<T: AsRef<[u8]>> PAM for DynImage<T> {
     fn encode(self) -> Vec<u8> {
         super::e!(self, |x| encode(x))
     }
 
+    #[cfg(feature = "std")]
+    fn encode_to(self, to: &mut impl Write) -> std::io::Result<()> {
+        super::e!(self, |x| PAM::encode_to(x, to))
+    }
+
     unsafe fn encode_into(x: Self, out: *mut u8) -> usize {
         super::e!(x, |x| PAM::encode_into(x, out))
     }
 }
 
-#[inline]
-unsafe fn encode_into<const N: usize>(
+/// Writes the header, then the body. Bitmaps are inverted, as PAM has 1 for white.
+fn write<S: Sink>(
     (buf, (w, h)): (&[u8], (u32, u32)),
-    out: *mut u8,
-    tupltype: &[u8; N],
+    tupltype: &[u8],
     depth: u8,
-) -> usize {
-    let mut o = out;
-    o.put(b'P'.join(MAGIC + b'0'));
-    o.put(*b"\nWIDTH ");
-    encodeu32(w, &mut o);
-    o.put(*b"\nHEIGHT ");
-    encodeu32(h, &mut o);
-    o.put(*b"\nDEPTH ");
-    o.push(depth + b'0');
-    o.put(*b"\nMAXVAL 255\n");
-    o.put(*b"TUPLTYPE ");
-    o.put(*tupltype);
-    o.put(*b"\nENDHDR\n");
-    if tupltype[..] == *b"BLACKANDWHITE" {
-        for &x in buf {
-            o.push(x ^ 1)
+    to: &mut S,
+) -> core::result::Result<(), S::Error> {
+    to.reserve(size(buf) + tupltype.len());
+    header((w, h), tupltype, depth, to)?;
+    if tupltype == b"BLACKANDWHITE" {
+        let mut row = Vec::with_capacity(w as usize);
+        for r in buf.chunks_exact(w as usize) {
+            row.clear();
+            row.extend(r.iter().map(|x| x ^ 1));
+            to.put(&row)?;
         }
-        o.sub_ptr(out)
+        Ok(())
     } else {
-        o.copy_from(buf.as_ptr(), buf.len());
-        o.sub_ptr(out) + buf.len()
+        to.put(buf)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Longest header [`header`] writes, without the tupltype.
+const HEADER: usize = 80;
+
+/// Puts the `P7` header, up to and including `ENDHDR`.
+fn header<S: Sink>(
+    (w, h): (u32, u32),
+    tupltype: &[u8],
+    depth: u8,
+    to: &mut S,
+) -> core::result::Result<(), S::Error> {
+    let mut buf = [0; HEADER];
+    let start = buf.as_mut_ptr();
+    let mut o = start;
+    // SAFETY: everything but the tupltype takes at most HEADER bytes.
+    let n = unsafe {
+        o.put(b'P'.join(MAGIC + b'0'));
+        o.put(*b"\nWIDTH ");
+        encodeu32(w, &mut o);
+        o.put(*b"\nHEIGHT ");
+        encodeu32(h, &mut o);
+        o.put(*b"\nDEPTH ");
+        encodeu32(depth as u32, &mut o);
+        o.put(*b"\nMAXVAL 255\n");
+        o.put(*b"TUPLTYPE ");
+        o.sub_ptr(start)
+    };
+    to.put(&buf[..n])?;
+    to.put(tupltype)?;
+    to.put(b"\nENDHDR\n")
+}
+
+#[derive(Clone, Copy, Debug)]
 /// Header for PAM images.
 pub struct PAMHeader {
     pub width: NonZeroU32,
@@ -167,8 +269,38 @@ pub struct PAMHeader {
     pub tupltype: Type,
 }
 
+impl PAMHeader {
+    /// Header of a `width` by `height` image of this tupltype, with a depth to match and a max of 255.
+    ///
+    /// # Panics
+    ///
+    /// If `width` or `height` is 0.
+    pub const fn new(width: u32, height: u32, tupltype: Type) -> Self {
+        Self {
+            width: NonZeroU32::new(width).expect("zero width"),
+            height: NonZeroU32::new(height).expect("zero height"),
+            depth: tupltype.bytes(),
+            max: 255,
+            tupltype,
+        }
+    }
+
+    /// Set the max value.
+    pub const fn with_max(mut self, max: u8) -> Self {
+        self.max = max;
+        self
+    }
+}
+
+impl Default for PAMHeader {
+    /// A 1x1 RGB header.
+    fn default() -> Self {
+        Self::new(1, 1, Type::RGB)
+    }
+}
+
 /// Tupltype. See [pam wikipedia page](https://en.wikipedia.org/wiki/Netpbm#PAM_graphics_format) for more informaiton.
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Type {
     /// Black and white bitmap type, corresponding to `BLACKANDWHITE`
     Bit,
@@ -180,16 +312,67 @@ pub enum Type {
     /// Gray with alpha. `GRAYSCALE_ALPHA`
     YA,
     RGBA,
+    /// Any other tupltype, with `depth` channels of raw bytes.
+    Other {
+        depth: u8,
+        name: TypeName,
+    },
+}
+
+/// Name of a custom [`Type::Other`] tupltype, of at most [`TypeName::MAX`] bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TypeName {
+    len: u8,
+    buf: [u8; Self::MAX],
+}
+
+impl TypeName {
+    /// Longest supported name, in bytes.
+    pub const MAX: usize = 64;
+
+    /// Name from these bytes, if they fit.
+    pub const fn new(x: &[u8]) -> Option<Self> {
+        if x.len() > Self::MAX {
+            return None;
+        }
+        let mut buf = [0; Self::MAX];
+        buf.split_at_mut(x.len()).0.copy_from_slice(x);
+        Some(Self {
+            len: x.len() as u8,
+            buf,
+        })
+    }
+
+    /// This name, a space, then `x`, if it all fits.
+    fn join(mut self, x: &[u8]) -> Option<Self> {
+        let at = self.len as usize;
+        let to = self.buf.get_mut(at..at + 1 + x.len())?;
+        to[0] = b' ';
+        to[1..].copy_from_slice(x);
+        self.len += 1 + x.len() as u8;
+        Some(self)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8] {
+        self.buf.split_at(self.len as usize).0
+    }
+}
+
+impl core::fmt::Debug for TypeName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "\"{}\"", self.as_bytes().escape_ascii())
+    }
 }
 
 impl Type {
-    const fn bytes(self) -> u8 {
+    const fn bytes(&self) -> u8 {
         use Type::*;
         match self {
             Bit | Y => 1,
             BitA | YA => 2,
             RGB => 3,
             RGBA => 4,
+            Other { depth, .. } => *depth,
         }
     }
 }
@@ -201,23 +384,104 @@ pub fn decode(x: impl AsRef<[u8]>) -> Result<DynImage<Vec<u8>>> {
     decode_wo_magic(x)
 }
 
+/// Decode a PAM image into a [`DynImage`], with some [`DecodeOptions`].
+///
+/// With [`DecodeOptions::strict`], data after the body that isnt whitespace or another PAM image is an error.
+/// The [`DecodeOptions::max_ops`] budget counts the header and body bytes.
+pub fn decode_with(x: impl AsRef<[u8]>, options: DecodeOptions) -> Result<DynImage<Vec<u8>>> {
+    let mut x = x.as_ref();
+    crate::decode::magic(&mut x);
+    decode_from(&mut x, options)
+}
+
 /// Decode a magicless PAM image.
 pub fn decode_wo_magic(mut x: &[u8]) -> Result<DynImage<Vec<u8>>> {
+    decode_from(&mut x, DecodeOptions::new())
+}
+
+/// Decode a PAM image of any depth and tupltype into its header and raw samples.
+///
+/// Unlike [`decode`], samples are not converted: `BLACKANDWHITE` images stay 0 and 1.
+pub fn decode_raw(x: impl AsRef<[u8]>) -> Result<(PAMHeader, Vec<u8>)> {
+    let mut x = x.as_ref();
+    crate::decode::magic(&mut x);
     let header = decode_pam_header(&mut x)?;
-    let mut alloc = Vec::with_capacity(
+    let n = header.tupltype.bytes() as usize
+        * header.width.get() as usize
+        * header.height.get() as usize;
+    let buf = x.get(..n).ok_or(Error::MissingData)?.to_vec();
+    Ok((header, buf))
+}
+
+/// Decodes a magicless PAM image, advancing `x` past it.
+pub(crate) fn decode_from(x: &mut &[u8], options: DecodeOptions) -> Result<DynImage<Vec<u8>>> {
+    let start = *x;
+    let header = decode_pam_header_with(x, options)?;
+    let n = header.tupltype.bytes() as usize
+        * header.width.get() as usize
+        * header.height.get() as usize;
+    if n as u64 > options.spend(start.len() - x.len())? {
+        return Err(Error::BudgetExceeded);
+    }
+    let mut alloc = Vec::with_capacity(n);
+    let n = unsafe { decode_inner(x, alloc.as_mut_ptr(), header)? };
+    unsafe { alloc.set_len(n) };
+    crate::decode::skip(x, n);
+    if options.strict {
+        let rest = x.trim_ascii_start();
+        if !rest.is_empty() && !rest.starts_with(b"P7") {
+            return Err(Error::TrailingData { extra: rest.len() });
+        }
+    }
+    // SAFETY: decode_inner wrote `n` bytes.
+    unsafe { dyn_image(header, alloc) }
+}
+
+/// Decodes a magicless PAM image from a buffered reader.
+#[cfg(feature = "std")]
+pub(crate) fn decode_reader(x: &mut impl std::io::BufRead) -> Result<DynImage<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        let at = head.len();
+        if x.read_until(b'\n', &mut head)? == 0 || head[at..].trim_ascii() == b"ENDHDR" {
+            break;
+        }
+    }
+    let header = decode_pam_header(&mut &head[..])?;
+    let mut buf = read_n(
+        x,
         header.tupltype.bytes() as usize
             * header.width.get() as usize
             * header.height.get() as usize,
-    );
-    let n = unsafe { decode_inner(x, alloc.as_mut_ptr(), header)? };
-    unsafe { alloc.set_len(n) };
+    )?;
+    match header.tupltype {
+        Type::Bit => buf.iter_mut().for_each(|x| *x = x.saturating_mul(0xff)),
+        Type::BitA => buf
+            .iter_mut()
+            .step_by(2)
+            .for_each(|x| *x = x.saturating_mul(0xff)),
+        Type::Y | Type::YA | Type::RGB | Type::RGBA | Type::Other { .. } => match header.max {
+            255 => {}
+            max => buf
+                .iter_mut()
+                .for_each(|x| *x = rescale(*x as u16, max as u16)),
+        },
+    }
+    // SAFETY: read exactly the images size.
+    unsafe { dyn_image(header, buf) }
+}
+
+/// # Safety
+///
+/// `buf` must hold exactly the pixels that the header describes.
+unsafe fn dyn_image(header: PAMHeader, buf: Vec<u8>) -> Result<DynImage<Vec<u8>>> {
+    let (w, h) = (header.width, header.height);
     Ok(match header.tupltype {
-        Type::Bit => unsafe { DynImage::Y(Image::new(header.width, header.height, alloc)) },
-        Type::Y => unsafe { DynImage::Y(Image::new(header.width, header.height, alloc)) },
-        Type::BitA => unsafe { DynImage::Ya(Image::new(header.width, header.height, alloc)) },
-        Type::YA => unsafe { DynImage::Ya(Image::new(header.width, header.height, alloc)) },
-        Type::RGB => unsafe { DynImage::Rgb(Image::new(header.width, header.height, alloc)) },
-        Type::RGBA => unsafe { DynImage::Rgba(Image::new(header.width, header.height, alloc)) },
+        Type::Bit | Type::Y | Type::Other { depth: 1, .. } => DynImage::Y(Image::new(w, h, buf)),
+        Type::BitA | Type::YA | Type::Other { depth: 2, .. } => DynImage::Ya(Image::new(w, h, buf)),
+        Type::RGB | Type::Other { depth: 3, .. } => DynImage::Rgb(Image::new(w, h, buf)),
+        Type::RGBA | Type::Other { depth: 4, .. } => DynImage::Rgba(Image::new(w, h, buf)),
+        Type::Other { depth, .. } => return Err(Error::UnsupportedDepth(depth)),
     })
 }
 
@@ -229,6 +493,9 @@ pub unsafe fn decode_inner(x: &[u8], mut into: *mut u8, header: PAMHeader) -> Re
     let n = header.tupltype.bytes() as usize
         * header.width.get() as usize
         * header.height.get() as usize;
+    if x.len() < n {
+        return Err(Error::MissingData);
+    }
     match header.tupltype {
         Type::Bit => x
             .iter()
@@ -241,55 +508,120 @@ pub unsafe fn decode_inner(x: &[u8], mut into: *mut u8, header: PAMHeader) -> Re
             .take(header.width.get() as usize * header.height.get() as usize)
             .map(|[&x, &a]| [x.saturating_mul(0xff), a])
             .for_each(|x| into.put(x)),
-        Type::Y | Type::YA | Type::RGB | Type::RGBA => {
-            if x.len() < n {
-                return Err(Error::MissingData);
-            }
-            into.copy_from(x.as_ptr(), n);
-        }
+        Type::Y | Type::YA | Type::RGB | Type::RGBA | Type::Other { .. } => match header.max {
+            255 => into.copy_from(x.as_ptr(), n),
+            max => x[..n]
+                .iter()
+                .for_each(|&x| into.push(rescale(x as u16, max as u16))),
+        },
     }
     Ok(n)
 }
 
-/// expects no magic
+/// Decodes the PAM header. Expects no magic.
+///
+/// Header lines may come in any order before `ENDHDR`; comments and unknown keys are skipped.
 pub fn decode_pam_header(x: &mut &[u8]) -> Result<PAMHeader> {
-    macro_rules! test {
-        ($for:literal else $e:ident) => {
-            if x.rd().ok_or(Error::$e)? != *$for {
-                return Err(Error::$e);
-            };
-        };
+    decode_pam_header_with(x, DecodeOptions::new())
+}
+
+/// Decode a PAM header, with some [`DecodeOptions`].
+///
+/// With [`DecodeOptions::strict`], a numeric field given twice is [`Error::DuplicateField`]. Otherwise, the last one wins.
+/// `TUPLTYPE` lines are joined by a space, as the spec asks.
+pub fn decode_pam_header_with(x: &mut &[u8], options: DecodeOptions) -> Result<PAMHeader> {
+    fn set<T>(field: &mut Option<T>, to: T, name: &'static str, strict: bool) -> Result<()> {
+        if strict && field.is_some() {
+            return Err(Error::DuplicateField(name));
+        }
+        *field = Some(to);
+        Ok(())
     }
-    test![b"WIDTH " else MissingWidth];
-    let width = NonZeroU32::new(read_til(x)?).ok_or(Error::ZeroWidth)?;
-    test![b"HEIGHT " else MissingHeight];
-    let height = NonZeroU32::new(read_til(x)?).ok_or(Error::ZeroHeight)?;
+    let (mut width, mut height, mut depth, mut max, mut name) = (None, None, None, None, None);
+    loop {
+        let end = x
+            .iter()
+            .position(|&x| x == b'\n')
+            .ok_or(Error::MissingData)?;
+        let (line, rest) = (x[..end].trim_ascii(), &x[end + 1..]);
+        let (key, value) = line.split_at(
+            line.iter()
+                .position(u8::is_ascii_whitespace)
+                .unwrap_or(line.len()),
+        );
+        let mut value = value.trim_ascii();
+        match key {
+            b"ENDHDR" => {
+                *x = rest;
+                break;
+            }
+            b"WIDTH" => set(
+                &mut width,
+                NonZeroU32::new(read_til(&mut value)?).ok_or(Error::ZeroWidth)?,
+                "WIDTH",
+                options.strict,
+            )?,
+            b"HEIGHT" => set(
+                &mut height,
+                NonZeroU32::new(read_til(&mut value)?).ok_or(Error::ZeroHeight)?,
+                "HEIGHT",
+                options.strict,
+            )?,
+            b"DEPTH" => set(
+                &mut depth,
+                read_til::<u8>(&mut value)?,
+                "DEPTH",
+                options.strict,
+            )?,
+            b"MAXVAL" => set(
+                &mut max,
+                match read_til::<u8>(&mut value)? {
+                    0 => return Err(Error::ZeroMax),
+                    x => x,
+                },
+                "MAXVAL",
+                options.strict,
+            )?,
+            // repeated tupltypes are joined by a space
+            b"TUPLTYPE" => {
+                name = match name {
+                    None => TypeName::new(value),
+                    Some(name) => name.join(value),
+                };
+                name.ok_or(Error::LongTupltype)?;
+            }
+            // comments, blank lines, and keys we dont know about
+            _ => {}
+        }
+        // advance only past lines that parsed, so errors point at their line
+        *x = rest;
+    }
+    let width = width.ok_or(Error::MissingWidth)?;
+    let height = height.ok_or(Error::MissingHeight)?;
     width.checked_mul(height).ok_or(Error::TooLarge)?;
-    test![b"DEPTH " else MissingDepth];
-    let depth = read_til::<u8>(x)?;
-    test![b"MAXVAL " else MissingMax];
-    let max = read_til::<u8>(x)?;
-    test![b"TUPLTYPE " else MissingTupltype];
-    let end = x
-        .iter()
-        .position(|&x| x == b'\n')
-        .ok_or(Error::MissingTupltype)?;
-    let tupltype = match &x[..end] {
+    let depth = depth.ok_or(Error::MissingDepth)?;
+    let name = name.ok_or(Error::MissingTupltype)?;
+    let tupltype = match name.as_bytes() {
+        _ if depth == 0 => return Err(Error::UnsupportedDepth(0)),
         b"BLACKANDWHITE" => Type::Bit,
         b"BLACKANDWHITE_ALPHA" => Type::BitA,
         b"GRAYSCALE" => Type::Y,
         b"GRAYSCALE_ALPHA" => Type::YA,
         b"RGB" => Type::RGB,
         b"RGB_ALPHA" => Type::RGBA,
-        _ => return Err(Error::MissingTupltype),
+        _ => Type::Other { depth, name },
     };
-    *x = &x[end..];
-    test![b"\nENDHDR\n" else MissingData];
+    if depth != tupltype.bytes() {
+        return Err(Error::DepthMismatch {
+            depth,
+            expected: tupltype.bytes(),
+        });
+    }
     Ok(PAMHeader {
         width,
         height,
         depth,
-        max,
+        max: max.ok_or(Error::MissingMax)?,
         tupltype,
     })
 }
@@ -386,3 +718,157 @@ fn test_rgba() {
         include_bytes!("../tdata/fimg-rainbow-transparent.imgbuf")
     );
 }
+
+#[test]
+fn test_header_order() {
+    let x = b"P7\nDEPTH 1\nTUPLTYPE GRAYSCALE\n# made by hand\nMAXVAL 255\nHEIGHT 1\nSOMEKEY x\nWIDTH 2\nENDHDR\n\x01\x02";
+    assert_eq!(&**decode(x).unwrap().buffer(), [1, 2]);
+    assert!(matches!(
+        decode(b"P7\nWIDTH 2\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\x01\x02"),
+        Err(Error::MissingHeight)
+    ));
+}
+
+#[test]
+fn test_maxval() {
+    let x = b"P7\nWIDTH 3\nHEIGHT 1\nDEPTH 1\nMAXVAL 15\nTUPLTYPE GRAYSCALE\nENDHDR\n\x00\x08\x0f";
+    assert_eq!(&**decode(x).unwrap().buffer(), [0, 136, 255]);
+    #[cfg(feature = "std")]
+    assert_eq!(crate::decode_reader(&x[..]).unwrap().bytes(), [0, 136, 255]);
+    assert_eq!(decode_raw(x).unwrap().1, [0, 8, 15]);
+}
+
+#[test]
+fn test_rgba_depth() {
+    let data = include_bytes!("../tdata/fimg-rainbow-transparent.imgbuf");
+    let out = PAM::encode(Image::<_, 4>::build(20, 15).buf(&data[..]));
+    assert!(out.split(|&x| x == b'\n').any(|line| line == b"DEPTH 4"));
+    let mut x = &out[3..];
+    assert_eq!(decode_pam_header(&mut x).unwrap().depth, 4);
+    assert_eq!(&**decode(&out).unwrap().buffer(), data);
+}
+
+#[test]
+fn test_depth_mismatch() {
+    assert!(matches!(
+        decode(
+            b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\x01\x02\x03"
+        ),
+        Err(Error::DepthMismatch {
+            depth: 3,
+            expected: 1
+        })
+    ));
+}
+
+#[test]
+fn test_custom_tupltype() {
+    let buf = (0..2 * 12).collect::<Vec<u8>>();
+    let out = encode_custom(Image::<_, 12>::build(2, 1).buf(&buf[..]), "MULTISPECTRAL").unwrap();
+    assert!(out.starts_with(
+        b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 12\nMAXVAL 255\nTUPLTYPE MULTISPECTRAL\nENDHDR\n"
+    ));
+    let (header, raw) = decode_raw(&out).unwrap();
+    assert!(matches!(
+        header.tupltype,
+        Type::Other { depth: 12, name } if name.as_bytes() == b"MULTISPECTRAL"
+    ));
+    assert_eq!(raw, buf);
+    assert!(matches!(decode(&out), Err(Error::UnsupportedDepth(12))));
+
+    let out = encode_custom(
+        Image::<_, 2>::build(1, 1).buf(&[1, 2][..]),
+        "DEPTH_CONFIDENCE",
+    )
+    .unwrap();
+    assert_eq!(decode(&out).unwrap().bytes(), [1, 2]);
+
+    let x = Image::<_, 1>::build(1, 1).buf(&[1][..]);
+    let longest = "X".repeat(TypeName::MAX);
+    let (header, raw) = decode_raw(encode_custom(x, &longest).unwrap()).unwrap();
+    assert!(matches!(
+        header.tupltype,
+        Type::Other { depth: 1, name } if name.as_bytes() == longest.as_bytes()
+    ));
+    assert_eq!(raw, [1]);
+    assert_eq!(
+        encode_custom(x, &(longest + "X")),
+        Err(crate::encode::Error::LongTupltype)
+    );
+    for bad in ["A\nB", "A\rB", "A\tB", "\0"] {
+        assert_eq!(
+            encode_custom(x, bad),
+            Err(crate::encode::Error::BadTupltype)
+        );
+    }
+}
+
+#[test]
+fn test_header_new() {
+    let x = PAMHeader::new(20, 15, Type::RGB);
+    assert_eq!(
+        (x.width.get(), x.height.get(), x.depth, x.max),
+        (20, 15, 3, 255)
+    );
+    assert_eq!(PAMHeader::new(1, 1, Type::BitA).with_max(1).max, 1);
+    let x = PAMHeader::new(
+        1,
+        1,
+        Type::Other {
+            depth: 9,
+            name: TypeName::new(b"SPECTRAL").unwrap(),
+        },
+    );
+    assert_eq!(x.depth, 9);
+}
+
+#[test]
+fn test_trailing_data() {
+    let strict = DecodeOptions::new().strict(true);
+    let mut x = include_bytes!("../tdata/fimg-gray.pam").to_vec();
+    x.extend(b"\n");
+    assert!(decode_with(&x, strict).is_ok());
+    x.extend(include_bytes!("../tdata/fimg-gray.pam"));
+    assert!(decode_with(&x, strict).is_ok());
+
+    let mut x = include_bytes!("../tdata/fimg-gray.pam").to_vec();
+    x.extend(b"garbage");
+    assert!(decode(&x).is_ok());
+    assert!(matches!(
+        decode_with(&x, strict),
+        Err(Error::TrailingData { extra: 7 })
+    ));
+}
+
+#[test]
+fn test_duplicate_field() {
+    let x =
+        b"P7\nWIDTH 3\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE GRAYSCALE\nENDHDR\n\x7f";
+    assert!(matches!(
+        decode_with(x, DecodeOptions::new().strict(true)),
+        Err(Error::DuplicateField("WIDTH"))
+    ));
+    let DynImage::Y(y) = decode_with(x, DecodeOptions::new()).unwrap() else {
+        panic!()
+    };
+    assert_eq!((y.width(), &y.buffer()[..]), (1, &[0x7f][..]));
+}
+
+#[test]
+fn test_joined_tupltype() {
+    let x = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\nTUPLTYPE FOO\nTUPLTYPE BAR\nENDHDR\n\x7f";
+    for options in [DecodeOptions::new(), DecodeOptions::new().strict(true)] {
+        let mut y = &x[3..];
+        let header = decode_pam_header_with(&mut y, options).unwrap();
+        assert!(matches!(
+            header.tupltype,
+            Type::Other { depth: 1, name } if name.as_bytes() == b"FOO BAR"
+        ));
+    }
+    let long = alloc::format!("TUPLTYPE {}\n", "X".repeat(TypeName::MAX / 2));
+    let x = alloc::format!("WIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 255\n{long}{long}ENDHDR\n");
+    assert!(matches!(
+        decode_pam_header(&mut x.as_bytes()),
+        Err(Error::LongTupltype)
+    ));
+}
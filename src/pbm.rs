@@ -4,8 +4,10 @@
 pub type Input<'a> = Image<&'a [bool], 1>;
 pub type Output = Image<Vec<bool>, 1>;
 pub type Uninit = fimg::uninit::Image<bool, 1>;
-use crate::encode::{encodeu32, P};
-use atools::prelude::*;
+#[cfg(feature = "std")]
+use crate::encode::Io;
+use crate::encode::{done, header, Ptr, Sink, P};
+use alloc::{string::String, vec::Vec};
 use fimg::Image;
 
 #[cfg(test)]
@@ -25,12 +27,37 @@ pub mod plain {
 
     /// Encode an <code>[Image]<[bool], 1></code> into a [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) ASCII Image.
     pub fn encode<T: AsRef<[bool]>>(x: Image<T, 1>) -> String {
-        let mut y = Vec::with_capacity(size(x.as_ref()));
-        let n = unsafe { encode_into(x.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        let mut y = Vec::new();
+        done(write(x.as_ref(), &mut y));
+        // SAFETY: only ascii was written
         unsafe { String::from_utf8_unchecked(y) }
     }
 
+    /// Encode an <code>[Image]<[bool], 1></code> into a [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) ASCII Image, writing it a row at a time.
+    #[cfg(feature = "std")]
+    pub fn encode_to<T: AsRef<[bool]>>(
+        x: Image<T, 1>,
+        to: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        write(x.as_ref(), &mut Io(to))
+    }
+
+    /// Writes the header, then a line of pixels per row.
+    fn write<S: Sink>(x: Input, to: &mut S) -> core::result::Result<(), S::Error> {
+        to.reserve(size(x.as_ref()));
+        header(MAGIC, (x.width(), x.height()), None, None, to)?;
+        let mut row = Vec::with_capacity(x.width() as usize * 2 + 1);
+        for r in x.buffer().chunks_exact(x.width() as _) {
+            row.clear();
+            // ' ' is cosmetic
+            row.extend(r.iter().flat_map(|&on| [encode_bool(on), b' ']));
+            // cosmetic
+            row.push(b'\n');
+            to.put(&row)?;
+        }
+        Ok(())
+    }
+
     crate::decode::dec_fn! {
         "Decode an ASCII [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) image into an <code>[Image]<[Box]<[bool]>, 1></code>"
     }
@@ -57,44 +84,43 @@ pub mod plain {
     /// Converts 0 to 255 and 1 to 0, for your u8 image experience.
     pub fn decode_body_into_u8(
         x: &[u8],
+        into: fimg::uninit::Image<u8, 1>,
+    ) -> Result<Image<Vec<u8>, 1>> {
+        decode_bytes_into_u8(x.iter().copied(), into)
+    }
+
+    /// [`decode_body_into_u8`], over any byte iterator.
+    pub(crate) fn decode_bytes_into_u8(
+        x: impl Iterator<Item = u8>,
         mut into: fimg::uninit::Image<u8, 1>,
     ) -> Result<Image<Vec<u8>, 1>> {
-        let mut out = into.buf().as_mut_ptr() as *mut u8;
-        let pixels = into.width() * into.height();
-        for &b in x
-            .iter()
-            .filter(|&&x| matches!(x, b'0' | b'1'))
-            .take(pixels as usize)
-        {
-            // SAFETY: iterator over `pixels` elements.
-            unsafe { out.push((b == b'0') as u8 * 0xff) };
-        }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < pixels as usize } {
-            return Err(Error::MissingData);
-        }
-        // SAFETY: checked that the pixels have been initialized.
+        let n = (into.width() * into.height()) as usize;
+        // SAFETY: the buffer holds `n` pixels.
+        unsafe { crate::decode::put_samples(samples(x), n, into.buf().as_mut_ptr().cast())? };
+        // SAFETY: put_samples initialized every pixel.
         Ok(unsafe { into.assume_init() })
     }
 
+    /// Plain pbm pixels, as `0` (black) or `255` (white).
+    pub(crate) fn samples(x: impl Iterator<Item = u8>) -> impl Iterator<Item = Result<u8>> {
+        x.filter(|&x| matches!(x, b'0' | b'1'))
+            .map(|b| Ok((b == b'0') as u8 * 0xff))
+    }
+
+    /// Length of a body holding `n` pixels.
+    pub(crate) fn body_len(x: &[u8], n: usize) -> usize {
+        x.iter()
+            .enumerate()
+            .filter(|(_, &x)| matches!(x, b'0' | b'1'))
+            .nth(n.saturating_sub(1))
+            .map_or(x.len(), |(i, _)| i + 1)
+    }
+
     #[doc = include_str!("encode_into.md")]
     pub unsafe fn encode_into(x: Input, out: *mut u8) -> usize {
-        let mut o = out;
-        o.put(b'P'.join(MAGIC + b'0'));
-        o.push(b' ');
-        encodeu32(x.width(), &mut o);
-        o.push(b' ');
-        encodeu32(x.height(), &mut o);
-        o.push(b'\n');
-        for row in x.buffer().chunks_exact(x.width() as _) {
-            for &on in row {
-                o.push(encode_bool(on));
-                // cosmetic
-                o.push(b' ');
-            }
-            // cosmetic
-            o.push(b'\n');
-        }
-        o.sub_ptr(out)
+        let mut o = Ptr::new(out);
+        done(write(x, &mut o));
+        o.written()
     }
 
     #[doc = include_str!("est.md")]
@@ -122,6 +148,13 @@ pub mod plain {
             tdata()
         )
     }
+
+    #[test]
+    fn test_no_trailing_newline() {
+        let x = include_bytes!("../tdata/no-newline.pbm");
+        assert_eq!(&**decode(x).unwrap().buffer(), [true, false, true]);
+        assert_eq!(crate::decode(x).unwrap().bytes(), [0, 255, 0]);
+    }
 }
 
 /// Module for handling raw (packed binary) [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) (black and white) images.
@@ -130,9 +163,8 @@ pub mod raw {
     pub const MAGIC: u8 = 4;
     /// Encode an <code>[Image]<[bool], 1></code> [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) Raw (packed binary) Image.
     pub fn encode<T: AsRef<[bool]>>(x: Image<T, 1>) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(x.as_ref()));
-        let n = unsafe { encode_into(x.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        let mut y = Vec::new();
+        done(write(x.as_ref(), &mut y));
         y
     }
 
@@ -142,53 +174,72 @@ pub mod raw {
 
     #[doc = include_str!("encode_into.md")]
     pub unsafe fn encode_into(x: Input, out: *mut u8) -> usize {
-        let mut o = out;
-        o.put(b'P'.join(MAGIC + b'0'));
-        o.push(b' ');
-        encodeu32(x.width(), &mut o);
-        o.push(b' ');
-        encodeu32(x.height(), &mut o);
-        o.push(b'\n');
-        x.buffer()
-            .chunks_exact(x.width() as _)
-            .flat_map(|x| x.chunks(8))
-            .map(|chunk| {
-                chunk
-                    .iter()
-                    .copied()
-                    .chain(std::iter::repeat(false).take(8 - chunk.len()))
-                    .zip(0u8..)
-                    .fold(0, |acc, (x, i)| acc | (x as u8) << 7 - i)
-            })
-            .for_each(|x| o.push(x));
-
-        o.sub_ptr(out)
+        let mut o = Ptr::new(out);
+        done(write(x, &mut o));
+        o.written()
     }
 
-    #[doc = include_str!("decode_body_into.md")]
-    pub fn decode_body_into(x: &[u8], mut into: Uninit) -> Result<Output> {
-        let mut out = into.buf().as_mut_ptr() as *mut bool;
-        let pixels = into.width() * into.height();
-        let padding = into.width() % 8;
-        for &x in x
-            .iter()
-            .copied()
-            // expand the bits
-            .flat_map(|b| atools::range::<8>().rev().map(|x| b & (1 << x) != 0))
-            // TODO skip?
-            .collect::<Vec<_>>()
-            .chunks_exact((into.width() + padding) as _)
-            .map(|x| &x[..into.width() as _])
-            .take(into.height() as _)
-            .flatten()
-        {
-            // SAFETY: took `width` * `height` pixels.
-            unsafe { out.push(x) };
+    /// Encode an <code>[Image]<[bool], 1></code> [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) Raw (packed binary) Image, writing each row as it is packed.
+    #[cfg(feature = "std")]
+    pub fn encode_to<T: AsRef<[bool]>>(
+        x: Image<T, 1>,
+        to: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        write(x.as_ref(), &mut Io(to))
+    }
+
+    /// Writes the header, then each row as it is packed.
+    fn write<S: Sink>(x: Input, to: &mut S) -> core::result::Result<(), S::Error> {
+        to.reserve(size(x.as_ref()));
+        header(MAGIC, (x.width(), x.height()), None, None, to)?;
+        let mut row = Vec::with_capacity(x.width().div_ceil(8) as usize);
+        for r in x.buffer().chunks_exact(x.width() as _) {
+            row.clear();
+            row.extend(pack(r));
+            to.put(&row)?;
         }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < pixels as usize } {
-            return Err(Error::MissingData);
+        Ok(())
+    }
+
+    /// Packs a row, MSB first, padding the last byte with zeroes.
+    fn pack(row: &[bool]) -> impl Iterator<Item = u8> + '_ {
+        row.chunks(8).map(|chunk| {
+            chunk
+                .iter()
+                .zip(0u8..)
+                .fold(0, |acc, (&x, i)| acc | (x as u8) << (7 - i))
+        })
+    }
+
+    /// Unpacks `height` rows of `width` bits, skipping the padding bits at the end of each row.
+    ///
+    /// # Safety
+    ///
+    /// `out` must have space for `width` * `height` elements.
+    pub(crate) unsafe fn unpack<T: Copy>(
+        x: &[u8],
+        width: u32,
+        height: u32,
+        mut out: *mut T,
+        f: impl Fn(bool) -> T,
+    ) -> Result<()> {
+        let row = width.div_ceil(8) as usize;
+        let x = x.get(..row * height as usize).ok_or(Error::MissingData)?;
+        for r in x.chunks_exact(row) {
+            for i in 0..width as usize {
+                // SAFETY: writes `width` * `height` elements.
+                unsafe { out.push(f(r[i / 8] & (0x80 >> (i % 8)) != 0)) };
+            }
         }
-        // SAFETY: checked that the pixels have been initialized.
+        Ok(())
+    }
+
+    #[doc = include_str!("decode_body_into.md")]
+    pub fn decode_body_into(x: &[u8], mut into: Uninit) -> Result<Output> {
+        let (w, h) = (into.width(), into.height());
+        // SAFETY: the buffer holds `width` * `height` pixels.
+        unsafe { unpack(x, w, h, into.buf().as_mut_ptr().cast(), |x| x)? };
+        // SAFETY: unpack initialized every pixel.
         Ok(unsafe { into.assume_init() })
     }
 
@@ -197,29 +248,14 @@ pub mod raw {
         x: &[u8],
         mut into: fimg::uninit::Image<u8, 1>,
     ) -> Result<Image<Vec<u8>, 1>> {
-        let mut out = into.buf().as_mut_ptr() as *mut u8;
-        let pixels = into.width() * into.height();
-        let padding = into.width() % 8;
-        for x in x
-            .iter()
-            .copied()
-            // expand the bits
-            .flat_map(|b| atools::range::<8>().rev().map(|x| b & (1 << x) == 0))
-            // TODO skip?
-            .collect::<Vec<_>>()
-            .chunks_exact((into.width() + padding) as _)
-            .map(|x| &x[..into.width() as _])
-            .take(into.height() as _)
-            .flatten()
-            .map(|&x| x as u8 * 0xff)
-        {
-            // SAFETY: took `height` * `width` pixels.
-            unsafe { out.push(x) };
-        }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < pixels as usize } {
-            return Err(Error::MissingData);
-        }
-        // SAFETY: checked that the pixels have been initialized.
+        let (w, h) = (into.width(), into.height());
+        // SAFETY: the buffer holds `width` * `height` pixels.
+        unsafe {
+            unpack(x, w, h, into.buf().as_mut_ptr().cast(), |x| {
+                (!x) as u8 * 0xff
+            })?
+        };
+        // SAFETY: unpack initialized every pixel.
         Ok(unsafe { into.assume_init() })
     }
     #[doc = include_str!("est.md")]
@@ -227,7 +263,7 @@ pub mod raw {
         2 // magic
             + 23 // w h
             + (x.len() / 8) // packed pixels
-            + ((x.width() as usize % 8 != 0) as usize * x.height() as usize) // padding
+            + (!x.width().is_multiple_of(8) as usize * x.height() as usize) // padding
     }
 
     #[test]
@@ -247,4 +283,36 @@ pub mod raw {
             include_bytes!("../tdata/fimgR.pbm")
         );
     }
+
+    #[test]
+    fn test_row_padding() {
+        for width in [1, 7, 8, 9, 17] {
+            let data = (0..width * 3).map(|x| x % 3 == 0).collect::<Vec<_>>();
+            let out = encode(Image::build(width, 3).buf(&data[..]));
+            assert_eq!(&**decode(&out).unwrap().buffer(), data);
+            assert_eq!(
+                &**crate::decode(&out).unwrap().buffer(),
+                data.iter().map(|&x| !x as u8 * 0xff).collect::<Vec<_>>()
+            );
+            assert!(matches!(
+                decode(&out[..out.len() - 1]),
+                Err(Error::MissingData)
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_encode_to() {
+        let data = (0..1001u32 * 517)
+            .map(|x| (x * 7 + x / 1001) % 5 < 2)
+            .collect::<Vec<_>>();
+        let image = Image::build(1001, 517).buf(&data[..]);
+        let mut out = vec![];
+        encode_to(image.as_ref(), &mut out).unwrap();
+        let mut y = Vec::with_capacity(size(image.as_ref()));
+        let n = unsafe { encode_into(image.as_ref(), y.as_mut_ptr()) };
+        unsafe { y.set_len(n) };
+        assert_eq!(out, y);
+    }
 }
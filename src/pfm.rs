@@ -0,0 +1,223 @@
+//! [Portable FloatMap](https://netpbm.sourceforge.net/doc/pfm.html) grayscale (`Pf`) or RGB (`PF`) [`f32`] image encoding and decoding.
+//!
+//! Rows are stored bottom to top. As its magic number is not a digit, this format is not handled by [`decode()`](crate::decode).
+use crate::decode::{read_til, skip, skip_comments, Error, Result};
+use alloc::{format, vec::Vec};
+use core::num::NonZeroU32;
+use fimg::{DynImage, Image};
+
+/// Encode this <code>[Image]<[f32], N></code> to a [PFM](https://netpbm.sourceforge.net/doc/pfm.html) image.
+///
+/// The sign of `scale` picks the byte order: negative is little endian, positive is big endian.
+/// A `scale` of zero, or one that is not finite, is [`encode::Error::BadScale`](crate::encode::Error::BadScale), as it would not decode.
+///
+/// ```
+/// # use pnm::pfm;
+/// # use fimg::Image;
+/// let out = pfm::encode(Image::<_, 1>::build(2, 1).buf(&[0.5f32, 1.0][..]), -1.0).unwrap();
+/// assert!(out.starts_with(b"Pf\n2 1\n-1\n"));
+/// ```
+pub fn encode(x: impl PFM, scale: f32) -> crate::encode::Result<Vec<u8>> {
+    // the same rule as decode_header
+    if scale == 0.0 || !scale.is_finite() {
+        return Err(crate::encode::Error::BadScale);
+    }
+    Ok(x.encode(scale))
+}
+
+#[doc(hidden)]
+pub trait PFM {
+    fn encode(self, scale: f32) -> Vec<u8>;
+}
+
+macro_rules! pfm {
+    ($n:literal, $magic:literal) => {
+        impl<T: AsRef<[f32]>> PFM for Image<T, $n> {
+            fn encode(self, scale: f32) -> Vec<u8> {
+                let x = self.as_ref();
+                let mut o = Vec::with_capacity(32 + x.buffer().len() * 4);
+                o.extend(format!("P{}\n{} {}\n{scale}\n", $magic, x.width(), x.height()).bytes());
+                for row in x.buffer().chunks_exact(x.width() as usize * $n).rev() {
+                    for &s in row {
+                        o.extend(match scale.is_sign_negative() {
+                            true => s.to_le_bytes(),
+                            false => s.to_be_bytes(),
+                        });
+                    }
+                }
+                o
+            }
+        }
+    };
+}
+pfm!(1, 'f');
+pfm!(3, 'F');
+
+/// Encode this <code>[Image]<[f16](https://docs.rs/half/latest/half/struct.f16.html), N></code> to a [PFM](https://netpbm.sourceforge.net/doc/pfm.html) image, widening each sample to a [`f32`].
+///
+/// See [`encode`].
+#[cfg(feature = "half")]
+pub fn encode_f16(x: impl PFMHalf, scale: f32) -> crate::encode::Result<Vec<u8>> {
+    x.encode_f16(scale)
+}
+
+#[doc(hidden)]
+#[cfg(feature = "half")]
+pub trait PFMHalf {
+    fn encode_f16(self, scale: f32) -> crate::encode::Result<Vec<u8>>;
+}
+
+#[cfg(feature = "half")]
+impl<T: AsRef<[half::f16]>, const N: usize> PFMHalf for Image<T, N>
+where
+    for<'a> Image<&'a [f32], N>: PFM,
+{
+    fn encode_f16(self, scale: f32) -> crate::encode::Result<Vec<u8>> {
+        let x = self.as_ref();
+        let buf = x.buffer().iter().map(|&x| x.to_f32()).collect::<Vec<_>>();
+        encode(
+            Image::<_, N>::build(x.width(), x.height()).buf(&buf[..]),
+            scale,
+        )
+    }
+}
+
+/// Header of a [PFM](https://netpbm.sourceforge.net/doc/pfm.html) image.
+#[derive(Debug, Clone, Copy)]
+pub struct PFMHeader {
+    pub width: NonZeroU32,
+    pub height: NonZeroU32,
+    /// Channel count, 1 or 3.
+    pub channels: u8,
+    /// Scale factor. Negative if the samples are little endian.
+    pub scale: f32,
+}
+
+/// Decode a [PFM](https://netpbm.sourceforge.net/doc/pfm.html) header, including the magic number.
+pub fn decode_header(x: &mut &[u8]) -> Result<PFMHeader> {
+    let channels = match **x {
+        [b'P', b'f', ..] => 1,
+        [b'P', b'F', ..] => 3,
+        _ => return Err(Error::MissingMagic),
+    };
+    skip(x, 2);
+    skip_comments(x);
+    let width = NonZeroU32::new(read_til(x)?).ok_or(Error::ZeroWidth)?;
+    skip_comments(x);
+    let height = NonZeroU32::new(read_til(x)?).ok_or(Error::ZeroHeight)?;
+    width.checked_mul(height).ok_or(Error::TooLarge)?;
+    skip_comments(x);
+    let end = x
+        .iter()
+        .position(u8::is_ascii_whitespace)
+        .ok_or(Error::MissingData)?;
+    let scale = core::str::from_utf8(&x[..end])
+        .ok()
+        .and_then(|x| x.parse::<f32>().ok())
+        .filter(|x| *x != 0.0 && x.is_finite())
+        .ok_or(Error::BadScale)?;
+    skip(x, end + 1);
+    Ok(PFMHeader {
+        width,
+        height,
+        channels,
+        scale,
+    })
+}
+
+/// Decode a [PFM](https://netpbm.sourceforge.net/doc/pfm.html) image into a [`DynImage::Y`] or [`DynImage::Rgb`], with rows top to bottom.
+pub fn decode(x: impl AsRef<[u8]>) -> Result<DynImage<Vec<f32>>> {
+    decode_map(x.as_ref(), |x| x)
+}
+
+/// Decode a [PFM](https://netpbm.sourceforge.net/doc/pfm.html) image like [`decode`], narrowing each sample to a [`f16`](https://docs.rs/half/latest/half/struct.f16.html).
+///
+/// This loses precision: [`f16`](https://docs.rs/half/latest/half/struct.f16.html) has an 11 bit significand, so samples are rounded to about 3 decimal digits,
+/// and values beyond ±65504 become infinite.
+#[cfg(feature = "half")]
+pub fn decode_f16(x: impl AsRef<[u8]>) -> Result<DynImage<Vec<half::f16>>> {
+    decode_map(x.as_ref(), half::f16::from_f32)
+}
+
+fn decode_map<T>(mut x: &[u8], f: impl Fn(f32) -> T) -> Result<DynImage<Vec<T>>> {
+    let header = decode_header(&mut x)?;
+    let (w, h) = (header.width.get(), header.height.get());
+    let row = w as usize * header.channels as usize;
+    let data = x.get(..row * h as usize * 4).ok_or(Error::MissingData)?;
+    let le = header.scale.is_sign_negative();
+    let buf = data
+        .chunks_exact(row * 4)
+        .rev()
+        .flat_map(|x| x.chunks_exact(4))
+        .map(|x| {
+            let x = x.try_into().unwrap();
+            f(match le {
+                true => f32::from_le_bytes(x),
+                false => f32::from_be_bytes(x),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(match header.channels {
+        1 => DynImage::Y(Image::build(w, h).buf(buf)),
+        _ => DynImage::Rgb(Image::build(w, h).buf(buf)),
+    })
+}
+
+#[test]
+fn test_roundtrip() {
+    let y = [0.0f32, 0.25, -1.5, 3.0e8, 1.0, f32::MIN_POSITIVE];
+    let x = encode(Image::<_, 1>::build(2, 3).buf(&y[..]), -1.0).unwrap();
+    assert!(x.starts_with(b"Pf\n2 3\n-1\n"));
+    // bottom row first, little endian
+    assert_eq!(x[x.len() - 24..x.len() - 20], 1.0f32.to_le_bytes());
+    assert!(decode_header(&mut &x[..]).unwrap().scale < 0.0);
+    let DynImage::Y(out) = decode(&x).unwrap() else {
+        panic!()
+    };
+    assert_eq!(out.take_buffer(), y);
+
+    let rgb = [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0];
+    let x = encode(Image::<_, 3>::build(1, 2).buf(&rgb[..]), 4.0).unwrap();
+    assert!(x.starts_with(b"PF\n1 2\n4\n"));
+    assert_eq!(x[x.len() - 12..x.len() - 8], 1.0f32.to_be_bytes());
+    assert_eq!(decode_header(&mut &x[..]).unwrap().scale, 4.0);
+    let DynImage::Rgb(out) = decode(&x).unwrap() else {
+        panic!()
+    };
+    assert_eq!(out.take_buffer(), rgb);
+}
+
+#[test]
+fn test_bad_scale() {
+    assert!(matches!(decode(b"Pf 1 1 0 \0\0\0\0"), Err(Error::BadScale)));
+    assert!(matches!(decode(b"Pf 1 1 x \0\0\0\0"), Err(Error::BadScale)));
+    assert!(matches!(decode(b"Pf 1 1 -1 \0\0"), Err(Error::MissingData)));
+    // scales that would not decode are not encoded
+    let x = || Image::<_, 1>::build(1, 1).buf(&[0.5f32][..]);
+    for scale in [0.0, -0.0, f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        assert_eq!(encode(x(), scale), Err(crate::encode::Error::BadScale));
+    }
+    for scale in [f32::MIN_POSITIVE, -f32::MAX] {
+        assert!(decode(encode(x(), scale).unwrap()).is_ok());
+    }
+}
+
+#[test]
+#[cfg(feature = "half")]
+fn test_f16() {
+    use half::f16;
+    let y = [0.0f32, 0.1, -1.5, 1000.3, 1.0, 3.0e-3];
+    let DynImage::Y(out) =
+        decode_f16(encode(Image::<_, 1>::build(2, 3).buf(&y[..]), 1.0).unwrap()).unwrap()
+    else {
+        panic!()
+    };
+    for (&a, &b) in y.iter().zip(out.buffer().iter()) {
+        assert!((a - f32::from(b)).abs() <= a.abs() * f16::EPSILON.to_f32());
+    }
+    let x = encode_f16(out.as_ref(), -1.0).unwrap();
+    let DynImage::Y(back) = decode_f16(&x).unwrap() else {
+        panic!()
+    };
+    assert_eq!(back.take_buffer(), out.take_buffer());
+}
@@ -3,83 +3,155 @@ pub(crate) const CHANNELS: usize = 1;
 pub type Input<'a> = Image<&'a [u8], 1>;
 pub type Output = Image<Vec<u8>, 1>;
 pub type Uninit = fimg::uninit::Image<u8, 1>;
-use crate::encode::{encodeu32, P};
-use atools::prelude::*;
+#[cfg(feature = "std")]
+use crate::encode::Io;
+use crate::encode::{comment_size, done, header, plain_row, raw_row, with_header, Ptr, Sink};
+use alloc::{string::String, vec::Vec};
 use fimg::Image;
 
 #[cfg(test)]
-fn tdata() -> &'static [u8] {
+const fn tdata() -> &'static [u8] {
     include_bytes!("../tdata/fimg-gray.imgbuf")
 }
 
-/// Module for handling plain ascii (human readable) [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) (Y) images.
-pub mod plain {
+/// Convert an <code>[Image]<[u8], 3></code> or <code>[Image]<[u8], 4></code> to grey, with the [Rec. 601](https://en.wikipedia.org/wiki/Rec._601) luma weights.
+///
+/// `Y = 0.299 R + 0.587 G + 0.114 B`, in fixed point (`(77 R + 150 G + 29 B + 128) >> 8`). Alpha is dropped.
+///
+/// ```
+/// # use fimg::Image;
+/// let x = pnm::pgm::to_luma(Image::<_, 3>::build(2, 1).buf(&[255, 0, 0, 255, 255, 255][..]));
+/// assert_eq!(x.buffer(), &[77, 255]);
+/// ```
+///
+/// Other channel counts do not compile:
+///
+/// ```compile_fail
+/// # use fimg::Image;
+/// pnm::pgm::to_luma(Image::<_, 2>::build(1, 1).buf(&[1, 2][..]));
+/// ```
+pub fn to_luma(x: impl Luma) -> Output {
+    x.to_luma()
+}
+
+#[doc(hidden)]
+pub trait Luma {
+    fn to_luma(self) -> Output;
+}
 
-    use crate::encode::encode_;
+macro_rules! luma {
+    ($n:literal) => {
+        impl<T: AsRef<[u8]>> Luma for Image<T, $n> {
+            fn to_luma(self) -> Output {
+                let x = self.as_ref();
+                let buf = x
+                    .buffer()
+                    .chunks_exact($n)
+                    .map(|x| {
+                        ((77 * x[0] as u32 + 150 * x[1] as u32 + 29 * x[2] as u32 + 128) >> 8) as u8
+                    })
+                    .collect();
+                Image::build(x.width(), x.height()).buf(buf)
+            }
+        }
+    };
+}
+luma!(3);
+luma!(4);
 
+/// Module for handling plain ascii (human readable) [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) (Y) images.
+pub mod plain {
     use super::*;
     pub const MAGIC: u8 = 2;
 
     /// Encode an <code>[Image]<[u8], 1></code> into a [PBM](https://en.wikipedia.org/wiki/Netpbm#PBM_example) ASCII Image.
     pub fn encode<T: AsRef<[u8]>>(x: Image<T, 1>) -> String {
-        let mut y = Vec::with_capacity(size(x.as_ref()));
-        let n = unsafe { encode_into(x.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        encode_commented(x.as_ref(), None)
+    }
+
+    /// [`encode`], with each line of `comment` after the magic number.
+    pub(crate) fn encode_commented(x: Input, comment: Option<&str>) -> String {
+        let mut y = Vec::new();
+        done(write(x, 255, comment, &mut y));
+        // SAFETY: only ascii was written
+        unsafe { String::from_utf8_unchecked(y) }
+    }
+
+    /// Encode an <code>[Image]<[u8], 1></code> into a [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) ASCII Image, writing it a row at a time.
+    #[cfg(feature = "std")]
+    pub fn encode_to<T: AsRef<[u8]>>(
+        x: Image<T, 1>,
+        to: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        write(x.as_ref(), 255, None, &mut Io(to))
+    }
+
+    /// Writes the header, then a line of samples per row, rescaled into `0..=max`.
+    fn write<S: Sink>(
+        x: Input,
+        max: u16,
+        comment: Option<&str>,
+        to: &mut S,
+    ) -> core::result::Result<(), S::Error> {
+        to.reserve(size(x) + comment.map_or(0, comment_size));
+        header(MAGIC, (x.width(), x.height()), Some(max), comment, to)?;
+        let mut row = Vec::with_capacity(x.width() as usize * 6 + 1);
+        for r in x.buffer().chunks_exact(x.width() as usize) {
+            row.clear();
+            plain_row(r, max, &mut row);
+            // cosmetic
+            row.push(b'\n');
+            to.put(&row)?;
+        }
+        Ok(())
+    }
+
+    /// Encode an <code>[Image]<[u8], 1></code> into a [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) ASCII Image with a maxval of `max`, rescaling each sample into `0..=max`.
+    ///
+    /// Rounds the same way as [`raw::encode_with_max`](super::raw::encode_with_max).
+    ///
+    /// # Panics
+    ///
+    /// If `max` is 0.
+    pub fn encode_with_max<T: AsRef<[u8]>>(x: Image<T, 1>, max: u16) -> String {
+        assert!(max != 0, "maxval must be positive");
+        let mut y = Vec::new();
+        done(write(x.as_ref(), max, None, &mut y));
+        // SAFETY: only ascii was written
         unsafe { String::from_utf8_unchecked(y) }
     }
 
+    /// Encode an <code>[Image]<[u8], 3></code> or <code>[Image]<[u8], 4></code> into a [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) ASCII Image, converting it to grey with [`to_luma`].
+    pub fn encode_luma(x: impl Luma) -> String {
+        encode(to_luma(x))
+    }
+
     crate::decode::dec_fn! {
         max "Decode an ASCII [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) image into an <code>[Image]<[Box]<[u8]>, 1></code>"
     }
 
     #[doc = include_str!("decode_body_into.md")]
-    pub fn decode_body_into(x: &[u8], mut into: Uninit, max: u8) -> Result<Output> {
-        let mut out = into.buf().as_mut_ptr() as *mut u8;
-        let pixels = into.width() * into.height();
-        for b in x
-            .split(u8::is_ascii_whitespace)
-            .filter(|x| !x.is_empty() && x.len() <= 3)
-            .filter(|x| x.iter().all(u8::is_ascii_digit))
-            .flat_map(|x| {
-                x.iter()
-                    .try_fold(0u8, |acc, &x| acc.checked_mul(10)?.checked_add(x - b'0'))
-            })
-            .map(|x| {
-                if max == 255 {
-                    x
-                } else {
-                    ((x as f32 / max as f32) * 255.) as u8
-                }
-            })
-            .take(pixels as usize)
-        {
-            // SAFETY: iterator over `pixels` elements.
-            unsafe { out.push(b) };
-        }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < pixels as usize } {
-            return Err(Error::MissingData);
-        }
-        // SAFETY: checked that the pixels have been initialized.
+    pub fn decode_body_into(x: &[u8], into: Uninit, max: u16) -> Result<Output> {
+        decode_samples_into(crate::decode::samples(x.iter().copied(), max), into)
+    }
+
+    /// Places these (already scaled) samples into the [`Uninit`] image.
+    pub(crate) fn decode_samples_into(
+        x: impl Iterator<Item = Result<u8>>,
+        mut into: Uninit,
+    ) -> Result<Output> {
+        let n = (into.width() * into.height()) as usize;
+        // SAFETY: the buffer holds `n` samples.
+        unsafe { crate::decode::put_samples(x, n, into.buf().as_mut_ptr().cast())? };
+        // SAFETY: put_samples initialized every sample.
         Ok(unsafe { into.assume_init() })
     }
 
     #[doc = include_str!("encode_into.md")]
     pub unsafe fn encode_into(x: Input, out: *mut u8) -> usize {
-        let mut o = out;
-        o.put(b'P'.join(MAGIC + b'0'));
-        o.push(b' ');
-        encodeu32(x.width(), &mut o);
-        o.push(b' ');
-        encodeu32(x.height(), &mut o);
-        o.put(*b" 255\n");
-        for row in x.buffer().chunks_exact(x.width() as _) {
-            for &on in row {
-                o.put(encode_(on));
-            }
-            // cosmetic
-            o.push(b'\n');
-        }
-        o.sub_ptr(out)
+        let mut o = Ptr::new(out);
+        done(write(x, 255, None, &mut o));
+        o.written()
     }
 
     #[doc = include_str!("est.md")]
@@ -98,6 +170,23 @@ pub mod plain {
         );
     }
 
+    #[test]
+    fn test_no_trailing_newline() {
+        let x = include_bytes!("../tdata/no-newline.pgm");
+        assert_eq!(&**decode(x).unwrap().buffer(), [3, 4, 5]);
+        assert_eq!(crate::decode(x).unwrap().bytes(), [3, 4, 5]);
+        #[cfg(feature = "std")]
+        assert_eq!(crate::decode_reader(&x[..]).unwrap().bytes(), [3, 4, 5]);
+    }
+
+    #[test]
+    fn test_extra_whitespace() {
+        let x = include_bytes!("../tdata/whitespace-plain.pgm");
+        assert_eq!(&**decode(x).unwrap().buffer(), b" \n\t");
+        #[cfg(feature = "std")]
+        assert_eq!(crate::decode_reader(&x[..]).unwrap().bytes(), b" \n\t");
+    }
+
     #[test]
     fn test_decode() {
         assert_eq!(
@@ -112,45 +201,113 @@ pub mod plain {
 /// Module for handling raw (binary) [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) (gray) images.
 pub mod raw {
     use super::*;
+    use crate::decode::raw_samples;
     pub const MAGIC: u8 = 5;
     /// Encode an <code>[Image]<[u8], 1></code> [PBM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) Raw (binary) Image.
     pub fn encode<T: AsRef<[u8]>>(x: Image<T, 1>) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(x.as_ref()));
-        let n = unsafe { encode_into(x.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        encode_commented(x.as_ref(), None)
+    }
+
+    /// [`encode`], with each line of `comment` after the magic number.
+    pub(crate) fn encode_commented(x: Input, comment: Option<&str>) -> Vec<u8> {
+        let mut y = Vec::new();
+        done(write(x, 255, comment, &mut y));
+        y
+    }
+
+    /// Encode an <code>[Image]<[u8], 1></code> [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) Raw (binary) Image with a maxval of `max`, rescaling each sample into `0..=max`.
+    ///
+    /// Samples take two (big endian) bytes when `max` is over 255.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is 0.
+    pub fn encode_with_max<T: AsRef<[u8]>>(x: Image<T, 1>, max: u16) -> Vec<u8> {
+        assert!(max != 0, "maxval must be positive");
+        let mut y = Vec::new();
+        done(write(x.as_ref(), max, None, &mut y));
+        y
+    }
+
+    /// Encode an <code>[Image]<[u8], 3></code> or <code>[Image]<[u8], 4></code> into a [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) Raw (binary) Image, converting it to grey with [`to_luma`].
+    pub fn encode_luma(x: impl Luma) -> Vec<u8> {
+        encode(to_luma(x))
+    }
+
+    /// Encode an <code>[Image]<[u8], 1></code> [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) Raw (binary) Image, with a maxval of its largest sample instead of 255.
+    ///
+    /// Samples are stored unchanged.
+    pub fn encode_tight<T: AsRef<[u8]>>(x: Image<T, 1>) -> Vec<u8> {
+        let x = x.as_ref();
+        // maxval must be positive
+        let max = x.buffer().iter().copied().max().unwrap_or(0).max(1);
+        let mut y = with_header(
+            MAGIC,
+            (x.width(), x.height()),
+            Some(max.into()),
+            x.buffer().len(),
+        );
+        y.extend_from_slice(x.buffer());
+        y
+    }
+
+    /// Encode an <code>[Image]<[u8], 1></code> [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) Raw (binary) Image into a writer.
+    #[cfg(feature = "std")]
+    pub fn encode_to<T: AsRef<[u8]>>(
+        x: Image<T, 1>,
+        to: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        write(x.as_ref(), 255, None, &mut Io(to))
+    }
+
+    /// Writes the header, then the samples, rescaled into `0..=max`.
+    fn write<S: Sink>(
+        x: Input,
+        max: u16,
+        comment: Option<&str>,
+        to: &mut S,
+    ) -> core::result::Result<(), S::Error> {
+        let each = crate::decode::sample_bytes(max);
+        to.reserve(size(x) + x.len() * (each - 1) + comment.map_or(0, comment_size));
+        header(MAGIC, (x.width(), x.height()), Some(max), comment, to)?;
+        if max == 255 {
+            return to.put(x.buffer());
+        }
+        let mut row = Vec::with_capacity(x.width() as usize * each);
+        for r in x.buffer().chunks_exact(x.width() as usize) {
+            row.clear();
+            raw_row(r, max, &mut row);
+            to.put(&row)?;
+        }
+        Ok(())
+    }
+
+    /// Encode an <code>[Image]<[bool], 1></code> mask into a [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) Raw (binary) Image.
+    ///
+    /// `true` becomes 255, `false` becomes 0.
+    pub fn encode_bool<T: AsRef<[bool]>>(x: Image<T, 1>) -> Vec<u8> {
+        let x = x.as_ref();
+        let mut y = with_header(MAGIC, (x.width(), x.height()), Some(255), x.len());
+        y.extend(x.buffer().iter().map(|&on| on as u8 * 0xff));
         y
     }
 
     crate::decode::dec_fn! {
-        "Decode a raw binary [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) image into an <code>[Image]<[Box]<[u8]>, 1></code>"
+        max "Decode a raw binary [PGM](https://en.wikipedia.org/wiki/Netpbm#PGM_example) image into an <code>[Image]<[Box]<[u8]>, 1></code>"
     }
 
     #[doc = include_str!("encode_into.md")]
     pub unsafe fn encode_into(x: Input, out: *mut u8) -> usize {
-        let mut o = out;
-        o.put(b'P'.join(MAGIC + b'0'));
-        o.push(b' ');
-        encodeu32(x.width(), &mut o);
-        o.push(b' ');
-        encodeu32(x.height(), &mut o);
-        o.put(*b" 255\n");
-        o.copy_from(x.buffer().as_ptr(), x.len());
-        o.sub_ptr(out) + x.len()
+        let mut o = Ptr::new(out);
+        done(write(x, 255, None, &mut o));
+        o.written()
     }
 
     #[doc = include_str!("decode_body_into.md")]
-    pub fn decode_body_into(x: &[u8], mut into: Uninit) -> Result<Output> {
-        let mut out = into.buf().as_mut_ptr() as *mut u8;
-        let pixels = into.width() * into.height();
-        for b in x.iter().copied().take(pixels as _) {
-            // SAFETY: took `pixels` pixels.
-            unsafe { out.push(b) };
-        }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < pixels as usize } {
-            return Err(Error::MissingData);
-        }
-        // SAFETY: checked that the pixels have been initialized.
-        Ok(unsafe { into.assume_init() })
+    ///
+    /// Samples are rescaled from `0..=max` to `0..=255`, and take two (big endian) bytes when `max` is over 255.
+    pub fn decode_body_into(x: &[u8], into: Uninit, max: u16) -> Result<Output> {
+        super::plain::decode_samples_into(raw_samples(x, max).map(Ok), into)
     }
 
     #[doc = include_str!("est.md")]
@@ -177,4 +334,57 @@ pub mod raw {
             include_bytes!("../tdata/fimgR.pgm")
         );
     }
+
+    #[test]
+    fn test_encode_bool() {
+        let mask = [true, false, false, true, true, false];
+        let out = encode_bool(Image::build(3, 2).buf(&mask[..]));
+        assert_eq!(out, b"P5 3 2 255\n\xff\x00\x00\xff\xff\x00");
+        assert_eq!(
+            &**decode(&out).unwrap().buffer(),
+            mask.map(|x| x as u8 * 0xff)
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let x = include_bytes!("../tdata/fimg-comments.pgm");
+        assert_eq!(&**decode(x).unwrap().buffer(), tdata());
+        #[cfg(feature = "std")]
+        assert_eq!(crate::decode_reader(&x[..]).unwrap().bytes(), tdata());
+        // comments right after a number, with no whitespace between them
+        for x in [&b"P5 1 1#c\n255\n\x07"[..], b"P5 1 1 255#c\n\x07"] {
+            assert_eq!(&**decode(x).unwrap().buffer(), [7]);
+            #[cfg(feature = "std")]
+            assert_eq!(crate::decode_reader(x).unwrap().bytes(), [7]);
+        }
+    }
+
+    #[test]
+    fn test_whitespace_pixel() {
+        let x = include_bytes!("../tdata/whitespace-raw.pgm");
+        assert_eq!(&**decode(x).unwrap().buffer(), b" \n\t");
+        #[cfg(feature = "std")]
+        assert_eq!(crate::decode_reader(&x[..]).unwrap().bytes(), b" \n\t");
+    }
+
+    #[test]
+    fn test_encode_luma() {
+        let rgba = [255, 0, 0, 9, 0, 255, 0, 9, 0, 0, 255, 9, 255, 255, 255, 9];
+        let out = encode_luma(Image::<_, 4>::build(2, 2).buf(&rgba[..]));
+        assert_eq!(out, b"P5 2 2 255\n\x4d\x95\x1d\xff");
+        let rgb = Image::<_, 3>::build(2, 2).buf(
+            rgba.chunks_exact(4)
+                .flat_map(|x| &x[..3])
+                .copied()
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(encode_luma(rgb.as_ref()), out);
+        assert_eq!(
+            crate::pgm::plain::decode(crate::pgm::plain::encode_luma(rgb))
+                .unwrap()
+                .buffer(),
+            decode(&out).unwrap().buffer()
+        );
+    }
 }
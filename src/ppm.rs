@@ -3,27 +3,76 @@ pub(crate) const CHANNELS: usize = 3;
 pub type Input<'a> = Image<&'a [u8], 3>;
 pub type Output = Image<Vec<u8>, 3>;
 pub type Uninit = fimg::uninit::Image<u8, 3>;
-use crate::encode::{encodeu32, P};
-use atools::prelude::*;
+#[cfg(feature = "std")]
+use crate::encode::Io;
+use crate::encode::{comment_size, done, header, plain_row, raw_row, with_header, Ptr, Sink};
+use alloc::{string::String, vec::Vec};
 use fimg::Image;
 
 #[cfg(test)]
-fn tdata() -> &'static [u8] {
+const fn tdata() -> &'static [u8] {
     include_bytes!("../tdata/fimg-rainbow.imgbuf")
 }
 
 /// Module for handling plain ascii (human readable) [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) (Y) images.
 pub mod plain {
-    use crate::encode::encode_;
-
     use super::*;
     pub const MAGIC: u8 = 3;
 
     /// Encode an <code>[Image]<[u8], 3></code> into a [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) ASCII Image.
     pub fn encode<T: AsRef<[u8]>>(x: Image<T, 3>) -> String {
-        let mut y = Vec::with_capacity(size(x.as_ref()));
-        let n = unsafe { encode_into(x.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        encode_commented(x.as_ref(), None)
+    }
+
+    /// [`encode`], with each line of `comment` after the magic number.
+    pub(crate) fn encode_commented(x: Input, comment: Option<&str>) -> String {
+        let mut y = Vec::new();
+        done(write(x, 255, comment, &mut y));
+        // SAFETY: only ascii was written
+        unsafe { String::from_utf8_unchecked(y) }
+    }
+
+    /// Encode an <code>[Image]<[u8], 3></code> into a [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) ASCII Image, writing it a row at a time.
+    #[cfg(feature = "std")]
+    pub fn encode_to<T: AsRef<[u8]>>(
+        x: Image<T, 3>,
+        to: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        write(x.as_ref(), 255, None, &mut Io(to))
+    }
+
+    /// Writes the header, then a line of samples per row, rescaled into `0..=max`.
+    fn write<S: Sink>(
+        x: Input,
+        max: u16,
+        comment: Option<&str>,
+        to: &mut S,
+    ) -> core::result::Result<(), S::Error> {
+        to.reserve(size(x) + comment.map_or(0, comment_size));
+        header(MAGIC, (x.width(), x.height()), Some(max), comment, to)?;
+        let mut row = Vec::with_capacity(x.width() as usize * 3 * 6 + 1);
+        for r in x.buffer().chunks_exact(x.width() as usize * 3) {
+            row.clear();
+            plain_row(r, max, &mut row);
+            // cosmetic
+            row.push(b'\n');
+            to.put(&row)?;
+        }
+        Ok(())
+    }
+
+    /// Encode an <code>[Image]<[u8], 3></code> into a [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) ASCII Image with a maxval of `max`, rescaling each sample into `0..=max`.
+    ///
+    /// Rounds the same way as [`raw::encode_with_max`](super::raw::encode_with_max).
+    ///
+    /// # Panics
+    ///
+    /// If `max` is 0.
+    pub fn encode_with_max<T: AsRef<[u8]>>(x: Image<T, 3>, max: u16) -> String {
+        assert!(max != 0, "maxval must be positive");
+        let mut y = Vec::new();
+        done(write(x.as_ref(), max, None, &mut y));
+        // SAFETY: only ascii was written
         unsafe { String::from_utf8_unchecked(y) }
     }
 
@@ -31,55 +80,57 @@ pub mod plain {
         max "Decode an ASCII [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) image into an <code>[Image]<[Box]<[u8]>, 3></code>"
     }
 
-    #[doc = include_str!("decode_body_into.md")]
-    pub fn decode_body_into(x: &[u8], mut into: Uninit, max: u8) -> Result<Output> {
-        let mut out = into.buf().as_mut_ptr() as *mut u8;
-        let pixels = into.width() * into.height();
-        for b in x
-            .split(u8::is_ascii_whitespace)
-            .filter(|x| !x.is_empty() && x.len() <= 3)
-            .filter(|x| x.iter().all(u8::is_ascii_digit))
-            .flat_map(|x| {
-                x.iter()
-                    .try_fold(0u8, |acc, &x| acc.checked_mul(10)?.checked_add(x - b'0'))
-            })
-            .map(|x| {
-                if max == 255 {
-                    x
-                } else {
-                    ((x as f32 / max as f32) * 255.) as u8
-                }
-            })
-            .array_chunks::<3>()
-            .take(pixels as usize)
-        {
-            // SAFETY: iterator over `pixels` elements.
-            unsafe { out.put(b) };
+    /// Lazily parse the samples of an ASCII [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) image, without decoding it into an image.
+    ///
+    /// Samples are not scaled, and the maxval may be up to 65535.
+    ///
+    /// ```
+    /// let x = pnm::ppm::plain::samples(b"P3 1 1 1000\n1000 0 7").unwrap();
+    /// assert_eq!(x.collect::<Result<Vec<_>, _>>().unwrap(), [1000, 0, 7]);
+    /// ```
+    pub fn samples(mut x: &[u8]) -> Result<impl Iterator<Item = Result<u16>> + '_> {
+        let magic = crate::decode::magic(&mut x).ok_or(Error::MissingMagic)?;
+        if magic != MAGIC {
+            return Err(Error::WrongMagic {
+                got: magic,
+                should: MAGIC,
+            });
         }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < (pixels as usize * 3) } {
-            return Err(Error::MissingData);
-        }
-        // SAFETY: checked that the pixels have been initialized.
+        let header = decode_header(&mut x, MAGIC)?;
+        let max = header.max.unwrap();
+        let mut tokens = crate::decode::tokens(x.iter().copied(), max);
+        let mut left = header.width.get() as usize * header.height.get() as usize * CHANNELS;
+        Ok(core::iter::from_fn(move || {
+            left = left.checked_sub(1)?;
+            Some(tokens.next().unwrap_or_else(|| {
+                left = 0;
+                Err(Error::MissingData)
+            }))
+        }))
+    }
+
+    #[doc = include_str!("decode_body_into.md")]
+    pub fn decode_body_into(x: &[u8], into: Uninit, max: u16) -> Result<Output> {
+        decode_samples_into(crate::decode::samples(x.iter().copied(), max), into)
+    }
+
+    /// Places these (already scaled) samples into the [`Uninit`] image.
+    pub(crate) fn decode_samples_into(
+        x: impl Iterator<Item = Result<u8>>,
+        mut into: Uninit,
+    ) -> Result<Output> {
+        let n = (into.width() * into.height()) as usize * 3;
+        // SAFETY: the buffer holds `n` samples.
+        unsafe { crate::decode::put_samples(x, n, into.buf().as_mut_ptr().cast())? };
+        // SAFETY: put_samples initialized every sample.
         Ok(unsafe { into.assume_init() })
     }
 
     #[doc = include_str!("encode_into.md")]
     pub unsafe fn encode_into(x: Input, out: *mut u8) -> usize {
-        let mut o = out;
-        o.put(b'P'.join(MAGIC + b'0'));
-        o.push(b' ');
-        encodeu32(x.width(), &mut o);
-        o.push(b' ');
-        encodeu32(x.height(), &mut o);
-        o.put(*b" 255\n");
-        for row in x.flatten().chunks_exact(x.width() as _) {
-            for &on in row.iter().flatten() {
-                o.put(encode_(on));
-            }
-            // cosmetic
-            o.push(b'\n');
-        }
-        o.sub_ptr(out)
+        let mut o = Ptr::new(out);
+        done(write(x, 255, None, &mut o));
+        o.written()
     }
 
     #[doc = include_str!("est.md")]
@@ -98,6 +149,32 @@ pub mod plain {
         );
     }
 
+    #[test]
+    fn test_samples() {
+        let x = samples(include_bytes!("../tdata/fimg-rainbowA.ppm")).unwrap();
+        assert!(x
+            .map(Result::unwrap)
+            .map(|x| x as u8)
+            .eq(tdata().iter().copied()));
+        let x = samples(b"P3 1 2 255\n1 x 300\n4 5")
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            x[..],
+            [
+                Ok(1),
+                Err(Error::NotDigit('x')),
+                Err(Error::OverMax {
+                    sample: 300,
+                    max: 255
+                }),
+                Ok(4),
+                Ok(5),
+                Err(Error::MissingData),
+            ]
+        ));
+    }
+
     #[test]
     fn test_decode() {
         assert_eq!(
@@ -107,50 +184,143 @@ pub mod plain {
             tdata()
         )
     }
+
+    #[test]
+    fn test_long_tokens() {
+        let x = b"P3 2 1 255\n0255 000 00001 255 0 0000000000000000007";
+        assert_eq!(&**decode(x).unwrap().buffer(), [255, 0, 1, 255, 0, 7]);
+        #[cfg(feature = "std")]
+        assert_eq!(
+            crate::decode_reader(&x[..]).unwrap().bytes(),
+            [255, 0, 1, 255, 0, 7]
+        );
+        // the full maxval range maps onto 0..=255
+        let x = b"P3 2 1 200\n0 100 200 0200 0 1";
+        assert_eq!(&**decode(x).unwrap().buffer(), [0, 128, 255, 255, 0, 1]);
+
+        assert!(matches!(
+            decode(b"P3 1 1 255\n1 2 999"),
+            Err(Error::OverMax {
+                sample: 999,
+                max: 255
+            })
+        ));
+        assert!(matches!(
+            decode(b"P3 1 1 255\n1 70000 3"),
+            Err(Error::Overflow)
+        ));
+        // 16 bit maxvals are scaled down at the end
+        let x = b"P3 1 1 65535\n0 32768 65535";
+        assert_eq!(&**decode(x).unwrap().buffer(), [0, 128, 255]);
+        assert!(matches!(
+            crate::decode(b"P3 1 1 200\n1 201 3"),
+            Err(Error::OverMax {
+                sample: 201,
+                max: 200
+            })
+        ));
+        assert!(matches!(
+            decode(b"P3 1 1 255\n1 2x 3"),
+            Err(Error::NotDigit('x'))
+        ));
+    }
 }
 
 /// Module for handling raw (binary) [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) (rgb) images.
 pub mod raw {
     use super::*;
+    use crate::decode::raw_samples;
     pub const MAGIC: u8 = 6;
     /// Encode an <code>[Image]<[u8], 3></code> [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) Raw (binary) Image.
     pub fn encode<T: AsRef<[u8]>>(x: Image<T, 3>) -> Vec<u8> {
-        let mut y = Vec::with_capacity(size(x.as_ref()));
-        let n = unsafe { encode_into(x.as_ref(), y.as_mut_ptr()) };
-        unsafe { y.set_len(n) };
+        encode_commented(x.as_ref(), None)
+    }
+
+    /// [`encode`], with each line of `comment` after the magic number.
+    pub(crate) fn encode_commented(x: Input, comment: Option<&str>) -> Vec<u8> {
+        let mut y = Vec::new();
+        done(write(x, 255, comment, &mut y));
+        y
+    }
+
+    /// Encode an <code>[Image]<[u8], 3></code> [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) Raw (binary) Image with a maxval of `max`, rescaling each sample into `0..=max`.
+    ///
+    /// Samples take two (big endian) bytes when `max` is over 255.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is 0.
+    pub fn encode_with_max<T: AsRef<[u8]>>(x: Image<T, 3>, max: u16) -> Vec<u8> {
+        assert!(max != 0, "maxval must be positive");
+        let mut y = Vec::new();
+        done(write(x.as_ref(), max, None, &mut y));
         y
     }
 
+    /// Encode an <code>[Image]<[u8], 3></code> [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) Raw (binary) Image, with a maxval of its largest sample instead of 255.
+    ///
+    /// Samples are stored unchanged.
+    pub fn encode_tight<T: AsRef<[u8]>>(x: Image<T, 3>) -> Vec<u8> {
+        let x = x.as_ref();
+        // maxval must be positive
+        let max = x.buffer().iter().copied().max().unwrap_or(0).max(1);
+        let mut y = with_header(
+            MAGIC,
+            (x.width(), x.height()),
+            Some(max.into()),
+            x.buffer().len(),
+        );
+        y.extend_from_slice(x.buffer());
+        y
+    }
+
+    /// Encode an <code>[Image]<[u8], 3></code> [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) Raw (binary) Image into a writer.
+    #[cfg(feature = "std")]
+    pub fn encode_to<T: AsRef<[u8]>>(
+        x: Image<T, 3>,
+        to: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        write(x.as_ref(), 255, None, &mut Io(to))
+    }
+
+    /// Writes the header, then the samples, rescaled into `0..=max`.
+    fn write<S: Sink>(
+        x: Input,
+        max: u16,
+        comment: Option<&str>,
+        to: &mut S,
+    ) -> core::result::Result<(), S::Error> {
+        let each = crate::decode::sample_bytes(max);
+        to.reserve(size(x) + x.len() * (each - 1) + comment.map_or(0, comment_size));
+        header(MAGIC, (x.width(), x.height()), Some(max), comment, to)?;
+        if max == 255 {
+            return to.put(x.buffer());
+        }
+        let mut row = Vec::with_capacity(x.width() as usize * 3 * each);
+        for r in x.buffer().chunks_exact(x.width() as usize * 3) {
+            row.clear();
+            raw_row(r, max, &mut row);
+            to.put(&row)?;
+        }
+        Ok(())
+    }
+
     crate::decode::dec_fn! {
-        "Decode a raw binary [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) image into an <code>[Image]<[Box]<[u8]>, 3></code>"
+        max "Decode a raw binary [PPM](https://en.wikipedia.org/wiki/Netpbm#PPM_example) image into an <code>[Image]<[Box]<[u8]>, 3></code>"
     }
 
     #[doc = include_str!("encode_into.md")]
     pub unsafe fn encode_into(x: Input, out: *mut u8) -> usize {
-        let mut o = out;
-        o.put(b'P'.join(MAGIC + b'0'));
-        o.push(b' ');
-        encodeu32(x.width(), &mut o);
-        o.push(b' ');
-        encodeu32(x.height(), &mut o);
-        o.put(*b" 255\n");
-        o.copy_from(x.buffer().as_ptr(), x.len());
-        o.sub_ptr(out) + x.len()
+        let mut o = Ptr::new(out);
+        done(write(x, 255, None, &mut o));
+        o.written()
     }
 
     #[doc = include_str!("decode_body_into.md")]
-    pub fn decode_body_into(x: &[u8], mut into: Uninit) -> Result<Output> {
-        let mut out = into.buf().as_mut_ptr() as *mut u8;
-        let pixels = into.width() * into.height();
-        for b in x.iter().copied().array_chunks::<3>().take(pixels as _) {
-            // SAFETY: took `pixels` pixels.
-            unsafe { out.put(b) };
-        }
-        if unsafe { out.sub_ptr(into.buf().as_mut_ptr().cast()) < (pixels as usize * 3) } {
-            return Err(Error::MissingData);
-        }
-        // SAFETY: checked that the pixels have been initialized.
-        Ok(unsafe { into.assume_init() })
+    ///
+    /// Samples are rescaled from `0..=max` to `0..=255`, and take two (big endian) bytes when `max` is over 255.
+    pub fn decode_body_into(x: &[u8], into: Uninit, max: u16) -> Result<Output> {
+        super::plain::decode_samples_into(raw_samples(x, max).map(Ok), into)
     }
 
     #[doc = include_str!("est.md")]